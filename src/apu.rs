@@ -0,0 +1,1235 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::gameboy::CLOCK_SPEED;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::Bits;
+
+pub const APU_START: u16 = 0xFF10;
+pub const APU_END: u16 = 0xFF3F;
+
+// Channel 1: square with sweep
+const NR10: u16 = 0xFF10;
+const NR11: u16 = 0xFF11;
+const NR12: u16 = 0xFF12;
+const NR13: u16 = 0xFF13;
+const NR14: u16 = 0xFF14;
+// Channel 2: square
+const NR21: u16 = 0xFF16;
+const NR22: u16 = 0xFF17;
+const NR23: u16 = 0xFF18;
+const NR24: u16 = 0xFF19;
+// Channel 3: wave
+const NR30: u16 = 0xFF1A;
+const NR31: u16 = 0xFF1B;
+const NR32: u16 = 0xFF1C;
+const NR33: u16 = 0xFF1D;
+const NR34: u16 = 0xFF1E;
+// Channel 4: noise
+const NR41: u16 = 0xFF20;
+const NR42: u16 = 0xFF21;
+const NR43: u16 = 0xFF22;
+const NR44: u16 = 0xFF23;
+// Master volume, panning and power
+const NR50: u16 = 0xFF24;
+const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+
+const WAVE_RAM_START: u16 = 0xFF30;
+
+const TRIGGER_BIT: u8 = 7;
+const LENGTH_ENABLE_BIT: u8 = 6;
+const POWER_BIT: u8 = 7;
+
+// Bits that read back as 1, from NR10 to 0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Unused
+];
+
+// Register values left by the boot ROM, trigger bits cleared
+const POST_BOOT_REGISTERS: [(u16, u8); 19] = [
+    (NR10, 0x80),
+    (NR11, 0xBF),
+    (NR12, 0xF3),
+    (NR13, 0xFF),
+    (NR14, 0x3F),
+    (NR21, 0x3F),
+    (NR22, 0x00),
+    (NR24, 0x3F),
+    (NR30, 0x7F),
+    (NR31, 0xFF),
+    (NR32, 0x9F),
+    (NR33, 0xFF),
+    (NR34, 0x3F),
+    (NR41, 0xFF),
+    (NR42, 0x00),
+    (NR43, 0x00),
+    (NR44, 0x3F),
+    (NR50, 0x77),
+    (NR51, 0xF3),
+];
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Samples not taken by the frontend are dropped past one second
+const MAX_BUFFERED_SECONDS: usize = 1;
+// Samples of each channel kept for oscilloscopes
+pub const SCOPE_SAMPLES: usize = 1024;
+
+// Shared by all channels, clocked at 256 Hz
+#[derive(Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    // Returns false once the channel must stop
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+}
+
+impl Stateful for Length {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Volume envelope of square and noise channels, clocked at 64 Hz
+#[derive(Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value.is_set(3);
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+impl Stateful for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.initial_volume);
+        state.write_bool(self.increase);
+        state.write_u8(self.period);
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = state.read_u8()?;
+        self.increase = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.volume = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// Frequency sweep of channel 1, clocked at 128 Hz
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow_frequency: u16,
+    enabled: bool,
+    // Leaving negate mode after using it stops the channel
+    negate_used: bool,
+}
+
+impl Sweep {
+    // Returns false if the channel must stop
+    fn write(&mut self, value: u8) -> bool {
+        self.period = (value >> 4) & 0x07;
+        self.negate = value.is_set(3);
+        self.shift = value & 0x07;
+        !self.negate_used || self.negate
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+
+    // Returns false if the frequency overflows
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.negate_used = false;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.shift == 0 || self.next_frequency() <= 2047
+    }
+
+    // Returns the new frequency if it changed, or Err on overflow
+    fn clock(&mut self) -> Result<Option<u16>, ()> {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return Ok(None);
+        }
+        self.reload_timer();
+
+        if !self.enabled || self.period == 0 {
+            return Ok(None);
+        }
+
+        let frequency = self.next_frequency();
+        if frequency > 2047 {
+            return Err(());
+        }
+        if self.shift == 0 {
+            return Ok(None);
+        }
+
+        self.shadow_frequency = frequency;
+        // Overflow is checked again with the new frequency
+        if self.next_frequency() > 2047 {
+            return Err(());
+        }
+        Ok(Some(frequency))
+    }
+}
+
+impl Stateful for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_u8(self.timer);
+        state.write_u16(self.shadow_frequency);
+        state.write_bool(self.enabled);
+        state.write_bool(self.negate_used);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.period = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        self.shadow_frequency = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        self.negate_used = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Square {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+        self.timer -= cycles;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    // Digital output, None when the DAC is off
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some(DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume)
+    }
+}
+
+impl Stateful for Square {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_position);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.duty = state.read_u8()?;
+        self.duty_position = state.read_u8()?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    // 0: mute, 1: 100%, 2: 50%, 3: 25%
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    // Index of the 4 bits sample played, out of 32
+    position: u8,
+    length: Length,
+}
+
+impl Wave {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+        }
+        self.timer -= cycles;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(256);
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn output(&self, wave_ram: &[u8; 16]) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+
+        let byte = wave_ram[self.position as usize / 2];
+        // High nibble is played first
+        let sample = if self.position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match self.volume_code {
+            0 => Some(0),
+            code => Some(sample >> (code - 1)),
+        }
+    }
+}
+
+impl Stateful for Wave {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.volume_code = state.read_u8()?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()?;
+        self.length.load_state(state)
+    }
+}
+
+#[derive(Default)]
+struct Noise {
+    enabled: bool,
+    dac_enabled: bool,
+    // 15 bits linear feedback shift register
+    lfsr: u16,
+    clock_shift: u8,
+    // Short mode, the LFSR is 7 bits long
+    width_7: bool,
+    divisor_code: u8,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn write_polynomial(&mut self, value: u8) {
+        self.clock_shift = value >> 4;
+        self.width_7 = value.is_set(3);
+        self.divisor_code = value & 0x07;
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.width_7 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        // Output is the inverted lowest bit
+        Some((!self.lfsr & 1) as u8 * self.envelope.volume)
+    }
+}
+
+impl Stateful for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u16(self.lfsr);
+        state.write_u8(self.clock_shift);
+        state.write_bool(self.width_7);
+        state.write_u8(self.divisor_code);
+        state.write_u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.lfsr = state.read_u16()?;
+        self.clock_shift = state.read_u8()?;
+        self.width_7 = state.read_bool()?;
+        self.divisor_code = state.read_u8()?;
+        self.timer = state.read_u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+// DACs output a DC offset, removed like the capacitor on the real board
+#[derive(Default)]
+struct HighPass {
+    capacitor: f32,
+}
+
+impl HighPass {
+    fn filter(&mut self, input: f32, charge_factor: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * charge_factor;
+        output
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Square1,
+        Channel::Square2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Channel::Square1 => write!(f, "Square 1"),
+            Channel::Square2 => write!(f, "Square 2"),
+            Channel::Wave => write!(f, "Wave"),
+            Channel::Noise => write!(f, "Noise"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvelopeInfo {
+    pub initial_volume: u8,
+    pub increase: bool,
+    // Steps of 1/64 s between volume changes, 0 stops the envelope
+    pub period: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepInfo {
+    // Steps of 1/128 s between frequency changes
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+}
+
+// Registers and state of a channel, decoded for debuggers. Fields
+// only some channels have are None on the others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelInfo {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub left: bool,
+    pub right: bool,
+    // Current volume, from 0 to 15. The wave channel shifts its samples
+    // instead, giving 15, 7 or 3 at most.
+    pub volume: u8,
+    // 0 to 3 for 12.5%, 25%, 50% and 75%
+    pub duty: Option<u8>,
+    pub envelope: Option<EnvelopeInfo>,
+    pub sweep: Option<SweepInfo>,
+    // Bits of the noise LFSR, 7 or 15
+    pub lfsr_width: Option<u8>,
+    // Of the tone, or of the LFSR clock for noise
+    pub frequency: f32,
+    // Length steps left, when the length counter stops the channel
+    pub length: Option<u16>,
+}
+
+impl fmt::Display for ChannelInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match (self.dac_enabled, self.enabled) {
+            (false, _) => "dac off",
+            (true, false) => "off",
+            (true, true) => "on",
+        };
+        let left = if self.left { "L" } else { "-" };
+        let right = if self.right { "R" } else { "-" };
+        write!(f, "{:<7} {}{} vol {:2}", status, left, right, self.volume)?;
+
+        if let Some(duty) = self.duty {
+            write!(f, " duty {}", ["12.5%", "25%", "50%", "75%"][duty as usize])?;
+        }
+        if let Some(envelope) = &self.envelope {
+            let direction = if envelope.increase { "+" } else { "-" };
+            write!(
+                f,
+                " env {}{}{}",
+                envelope.initial_volume, direction, envelope.period
+            )?;
+        }
+        if let Some(sweep) = &self.sweep {
+            let direction = if sweep.negate { "-" } else { "+" };
+            write!(f, " sweep {}{}{}", sweep.period, direction, sweep.shift)?;
+        }
+        if let Some(width) = self.lfsr_width {
+            write!(f, " lfsr {} bits", width)?;
+        }
+        write!(f, " {:.1} Hz", self.frequency)?;
+        if let Some(length) = self.length {
+            write!(f, " length {}", length)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Apu {
+    powered: bool,
+    // Raw values written to NR10-NR51
+    registers: [u8; 0x20],
+    wave_ram: [u8; 16],
+    square1: Square,
+    sweep: Sweep,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    // 8 steps clocked at 512 Hz by DIV
+    frame_step: u8,
+
+    // Host side, not part of the save state
+    sample_rate: u32,
+    sample_cycles: u64,
+    charge_factor: f32,
+    high_pass: [HighPass; 2],
+    // Interleaved left and right samples, between -1 and 1
+    samples: Vec<f32>,
+    // Debugging, by channel
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    scopes: [VecDeque<f32>; 4],
+}
+
+fn charge_factor(sample_rate: u32) -> f32 {
+    if sample_rate == 0 {
+        return 0.0;
+    }
+    0.999958f32.powf(CLOCK_SPEED as f32 / sample_rate as f32)
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu {
+            powered: false,
+            registers: [0; 0x20],
+            wave_ram: [0; 16],
+            square1: Square::default(),
+            sweep: Sweep::default(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            frame_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_cycles: 0,
+            charge_factor: charge_factor(DEFAULT_SAMPLE_RATE),
+            high_pass: [HighPass::default(), HighPass::default()],
+            samples: Vec::new(),
+            muted: [false; 4],
+            soloed: [false; 4],
+            scopes: Default::default(),
+        }
+    }
+}
+
+impl Apu {
+    // State left by the boot ROM. Its sound ends with channel 1
+    // still on at volume 0, except on SGB.
+    pub fn post_boot(channel1_on: bool) -> Self {
+        let mut apu = Apu::default();
+        apu.writeb(NR52, 0x80);
+        for (addr, value) in POST_BOOT_REGISTERS {
+            apu.writeb(addr, value);
+        }
+        apu.square1.enabled = channel1_on;
+        apu
+    }
+
+    // Host sample rate, 0 stops producing samples
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_cycles = 0;
+        self.charge_factor = charge_factor(sample_rate);
+        self.samples.clear();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Samples produced since the last call, interleaved left and right
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn update(&mut self, cycles: u32) {
+        if self.powered {
+            self.square1.step(cycles);
+            self.square2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        if self.sample_rate == 0 {
+            return;
+        }
+
+        self.sample_cycles += cycles as u64 * self.sample_rate as u64;
+        while self.sample_cycles >= CLOCK_SPEED as u64 {
+            self.sample_cycles -= CLOCK_SPEED as u64;
+            self.push_sample();
+        }
+    }
+
+    fn push_sample(&mut self) {
+        let outputs = self.outputs();
+        for (scope, output) in self.scopes.iter_mut().zip(outputs) {
+            if scope.len() == SCOPE_SAMPLES {
+                scope.pop_front();
+            }
+            scope.push_back(output.unwrap_or(0) as f32 / 15.0);
+        }
+
+        let (left, right) = self.mix(&outputs);
+        let left = self.high_pass[0].filter(left, self.charge_factor);
+        let right = self.high_pass[1].filter(right, self.charge_factor);
+
+        let max = self.sample_rate as usize * 2 * MAX_BUFFERED_SECONDS;
+        if self.samples.len() < max {
+            self.samples.push(left);
+            self.samples.push(right);
+        }
+    }
+
+    // Digital output of each channel, None when its DAC is off
+    fn outputs(&self) -> [Option<u8>; 4] {
+        [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(&self.wave_ram),
+            self.noise.output(),
+        ]
+    }
+
+    // Left and right analog output, between -1 and 1
+    fn mix(&self, outputs: &[Option<u8>; 4]) -> (f32, f32) {
+        let soloing = self.soloed.contains(&true);
+        let panning = self.registers[(NR51 - APU_START) as usize];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (channel, output) in outputs.iter().enumerate() {
+            if self.muted[channel] || (soloing && !self.soloed[channel]) {
+                continue;
+            }
+            // DACs map 0..15 to 1..-1, disabled ones output nothing
+            let analog = match output {
+                Some(digital) => 1.0 - *digital as f32 / 7.5,
+                None => 0.0,
+            };
+            if panning.is_set(channel as u8 + 4) {
+                left += analog;
+            }
+            if panning.is_set(channel as u8) {
+                right += analog;
+            }
+        }
+
+        let volume = self.registers[(NR50 - APU_START) as usize];
+        let left_volume = ((volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (volume & 0x07) as f32 + 1.0;
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+
+    // Silences a channel in the sound produced, the game still sees it playing
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // When any channel is soloed, only soloed channels are heard
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.soloed[channel as usize] = soloed;
+    }
+
+    pub fn is_soloed(&self, channel: Channel) -> bool {
+        self.soloed[channel as usize]
+    }
+
+    // Latest output levels of the channel, oldest first, between 0 and 1.
+    // Taken at the host sample rate, muted or not.
+    pub fn scope(&self, channel: Channel) -> &VecDeque<f32> {
+        &self.scopes[channel as usize]
+    }
+
+    pub fn wave_ram(&self) -> &[u8; 16] {
+        &self.wave_ram
+    }
+
+    pub fn channel_info(&self, channel: Channel) -> ChannelInfo {
+        let panning = self.registers[(NR51 - APU_START) as usize];
+        let index = channel as u8;
+        let mut info = ChannelInfo {
+            enabled: false,
+            dac_enabled: false,
+            left: panning.is_set(index + 4),
+            right: panning.is_set(index),
+            volume: 0,
+            duty: None,
+            envelope: None,
+            sweep: None,
+            lfsr_width: None,
+            frequency: 0.0,
+            length: None,
+        };
+        let envelope_info = |envelope: &Envelope| EnvelopeInfo {
+            initial_volume: envelope.initial_volume,
+            increase: envelope.increase,
+            period: envelope.period,
+        };
+        let length_info = |length: &Length| length.enabled.then_some(length.counter);
+
+        match channel {
+            Channel::Square1 | Channel::Square2 => {
+                let square = match channel {
+                    Channel::Square1 => &self.square1,
+                    _ => &self.square2,
+                };
+                info.enabled = square.enabled;
+                info.dac_enabled = square.dac_enabled;
+                info.volume = square.envelope.volume;
+                info.duty = Some(square.duty);
+                info.envelope = Some(envelope_info(&square.envelope));
+                info.frequency = CLOCK_SPEED as f32 / (square.period() * 8) as f32;
+                info.length = length_info(&square.length);
+            }
+            Channel::Wave => {
+                info.enabled = self.wave.enabled;
+                info.dac_enabled = self.wave.dac_enabled;
+                info.volume = match self.wave.volume_code {
+                    0 => 0,
+                    code => 15 >> (code - 1),
+                };
+                info.frequency = CLOCK_SPEED as f32 / (self.wave.period() * 32) as f32;
+                info.length = length_info(&self.wave.length);
+            }
+            Channel::Noise => {
+                info.enabled = self.noise.enabled;
+                info.dac_enabled = self.noise.dac_enabled;
+                info.volume = self.noise.envelope.volume;
+                info.envelope = Some(envelope_info(&self.noise.envelope));
+                info.lfsr_width = Some(if self.noise.width_7 { 7 } else { 15 });
+                info.frequency = CLOCK_SPEED as f32 / self.noise.period() as f32;
+                info.length = length_info(&self.noise.length);
+            }
+        }
+        if channel == Channel::Square1 {
+            info.sweep = Some(SweepInfo {
+                period: self.sweep.period,
+                negate: self.sweep.negate,
+                shift: self.sweep.shift,
+            });
+        }
+        info
+    }
+
+    // Falling edge of DIV bit 4
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+
+        if self.frame_step.is_multiple_of(2) {
+            self.clock_lengths();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.square1.enabled &= self.square1.length.clock();
+        self.square2.enabled &= self.square2.length.clock();
+        self.wave.enabled &= self.wave.length.clock();
+        self.noise.enabled &= self.noise.length.clock();
+    }
+
+    fn clock_sweep(&mut self) {
+        match self.sweep.clock() {
+            Ok(Some(frequency)) => {
+                self.square1.frequency = frequency;
+                self.registers[(NR13 - APU_START) as usize] = frequency as u8;
+                let nr14 = &mut self.registers[(NR14 - APU_START) as usize];
+                *nr14 = (*nr14 & 0xF8) | (frequency >> 8) as u8;
+            }
+            Ok(None) => (),
+            Err(()) => self.square1.enabled = false,
+        }
+    }
+
+    fn channel_status(&self) -> u8 {
+        [
+            self.square1.enabled,
+            self.square2.enabled,
+            self.wave.enabled,
+            self.noise.enabled,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |status, (channel, enabled)| {
+            status | (*enabled as u8) << channel
+        })
+    }
+
+    pub fn readb(&self, addr: u16) -> u8 {
+        match addr {
+            NR52 => (self.powered as u8) << POWER_BIT | 0x70 | self.channel_status(),
+            APU_START..=0xFF2F => {
+                let index = (addr - APU_START) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            WAVE_RAM_START..=APU_END => self.wave_ram[(addr - WAVE_RAM_START) as usize],
+            _ => 0xFF, // Not an APU register
+        }
+    }
+
+    pub fn writeb(&mut self, addr: u16, value: u8) {
+        match addr {
+            NR52 => self.set_power(value.is_set(POWER_BIT)),
+            WAVE_RAM_START..=APU_END => self.wave_ram[(addr - WAVE_RAM_START) as usize] = value,
+            // Registers are read-only while powered off
+            _ if !self.powered => (),
+            APU_START..=NR51 => {
+                self.registers[(addr - APU_START) as usize] = value;
+                self.write_register(addr, value);
+            }
+            _ => (),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            NR10 => self.square1.enabled &= self.sweep.write(value),
+            NR11 => {
+                self.square1.duty = value >> 6;
+                self.square1.length.counter = 64 - (value & 0x3F) as u16;
+            }
+            NR12 => {
+                self.square1.envelope.write(value);
+                self.square1.dac_enabled = value & 0xF8 != 0;
+                self.square1.enabled &= self.square1.dac_enabled;
+            }
+            NR13 => self.square1.frequency = (self.square1.frequency & 0x700) | value as u16,
+            NR14 => {
+                self.square1.frequency =
+                    (self.square1.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.square1.length.enabled = value.is_set(LENGTH_ENABLE_BIT);
+                if value.is_set(TRIGGER_BIT) {
+                    self.square1.trigger();
+                    if !self.sweep.trigger(self.square1.frequency) {
+                        self.square1.enabled = false;
+                    }
+                }
+            }
+            NR21 => {
+                self.square2.duty = value >> 6;
+                self.square2.length.counter = 64 - (value & 0x3F) as u16;
+            }
+            NR22 => {
+                self.square2.envelope.write(value);
+                self.square2.dac_enabled = value & 0xF8 != 0;
+                self.square2.enabled &= self.square2.dac_enabled;
+            }
+            NR23 => self.square2.frequency = (self.square2.frequency & 0x700) | value as u16,
+            NR24 => {
+                self.square2.frequency =
+                    (self.square2.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.square2.length.enabled = value.is_set(LENGTH_ENABLE_BIT);
+                if value.is_set(TRIGGER_BIT) {
+                    self.square2.trigger();
+                }
+            }
+            NR30 => {
+                self.wave.dac_enabled = value.is_set(7);
+                self.wave.enabled &= self.wave.dac_enabled;
+            }
+            NR31 => self.wave.length.counter = 256 - value as u16,
+            NR32 => self.wave.volume_code = (value >> 5) & 0x03,
+            NR33 => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            NR34 => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.wave.length.enabled = value.is_set(LENGTH_ENABLE_BIT);
+                if value.is_set(TRIGGER_BIT) {
+                    self.wave.trigger();
+                }
+            }
+            NR41 => self.noise.length.counter = 64 - (value & 0x3F) as u16,
+            NR42 => {
+                self.noise.envelope.write(value);
+                self.noise.dac_enabled = value & 0xF8 != 0;
+                self.noise.enabled &= self.noise.dac_enabled;
+            }
+            NR43 => self.noise.write_polynomial(value),
+            NR44 => {
+                self.noise.length.enabled = value.is_set(LENGTH_ENABLE_BIT);
+                if value.is_set(TRIGGER_BIT) {
+                    self.noise.trigger();
+                }
+            }
+            _ => (), // NR50, NR51 and unused registers are only stored
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.frame_step = 0;
+            self.square1.duty_position = 0;
+            self.square2.duty_position = 0;
+            self.wave.position = 0;
+        } else if !on && self.powered {
+            // Powering off clears every register but wave RAM
+            self.registers = [0; 0x20];
+            self.square1 = Square::default();
+            self.sweep = Sweep::default();
+            self.square2 = Square::default();
+            self.wave = Wave::default();
+            self.noise = Noise::default();
+        }
+        self.powered = on;
+    }
+}
+
+impl Stateful for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.powered);
+        state.write_bytes(&self.registers);
+        state.write_bytes(&self.wave_ram);
+        self.square1.save_state(state);
+        self.sweep.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_u8(self.frame_step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.powered = state.read_bool()?;
+        state.read_bytes(&mut self.registers)?;
+        state.read_bytes(&mut self.wave_ram)?;
+        self.square1.load_state(state)?;
+        self.sweep.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.frame_step = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered() -> Apu {
+        let mut apu = Apu::default();
+        apu.writeb(NR52, 0x80);
+        apu
+    }
+
+    #[test]
+    fn registers_read_back_with_unused_bits_set() {
+        let mut apu = powered();
+        apu.writeb(NR11, 0x80);
+        assert_eq!(apu.readb(NR11), 0xBF);
+        apu.writeb(NR13, 0x12);
+        assert_eq!(apu.readb(NR13), 0xFF);
+        assert_eq!(apu.readb(0xFF27), 0xFF);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = powered();
+        apu.writeb(NR50, 0x77);
+        apu.writeb(WAVE_RAM_START, 0x12);
+        apu.writeb(NR52, 0x00);
+        assert_eq!(apu.readb(NR50), 0x00);
+        assert_eq!(apu.readb(NR52), 0x70);
+        assert_eq!(apu.readb(WAVE_RAM_START), 0x12);
+
+        // Ignored while off
+        apu.writeb(NR50, 0x77);
+        assert_eq!(apu.readb(NR50), 0x00);
+    }
+
+    #[test]
+    fn length_stops_channel() {
+        let mut apu = powered();
+        apu.writeb(NR22, 0xF0);
+        apu.writeb(NR21, 0x3E); // 2 steps left
+        apu.writeb(NR24, 0xC0); // Trigger with length
+        assert_eq!(apu.readb(NR52) & 0x02, 0x02);
+
+        // Length is clocked every other step
+        for _ in 0..3 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.readb(NR52) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn dac_off_disables_trigger() {
+        let mut apu = powered();
+        apu.writeb(NR42, 0x00);
+        apu.writeb(NR44, 0x80);
+        assert_eq!(apu.readb(NR52) & 0x08, 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_stops_channel() {
+        let mut apu = powered();
+        apu.writeb(NR12, 0xF0);
+        apu.writeb(NR10, 0x11); // Period 1, shift 1, increasing
+        apu.writeb(NR13, 0xFF);
+        apu.writeb(NR14, 0x87); // Frequency 2047
+        assert_eq!(apu.readb(NR52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn samples_follow_host_rate() {
+        let mut apu = powered();
+        apu.set_sample_rate(48000);
+        for _ in 0..CLOCK_SPEED / 16 {
+            apu.update(16);
+        }
+        assert_eq!(apu.take_samples().len(), 48000 * 2);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    // Loudest sample of a square wave on channel 2, over 1/64 s
+    fn channel_2_peak(apu: &mut Apu) -> f32 {
+        apu.writeb(NR50, 0x77);
+        apu.writeb(NR51, 0xFF);
+        apu.writeb(NR22, 0xF0);
+        apu.writeb(NR21, 0x80);
+        apu.writeb(NR24, 0x87);
+        for _ in 0..CLOCK_SPEED / 64 / 16 {
+            apu.update(16);
+        }
+        apu.take_samples()
+            .iter()
+            .fold(0.0, |peak, sample| sample.abs().max(peak))
+    }
+
+    #[test]
+    fn channels_can_be_muted_or_soloed() {
+        assert!(channel_2_peak(&mut powered()) > 0.1);
+
+        let mut apu = powered();
+        apu.set_muted(Channel::Square2, true);
+        assert_eq!(channel_2_peak(&mut apu), 0.0);
+        // Still plays for the game and the scope
+        assert_eq!(apu.readb(NR52) & 0x02, 0x02);
+        assert!(apu.scope(Channel::Square2).contains(&1.0));
+
+        let mut apu = powered();
+        apu.set_soloed(Channel::Square1, true);
+        assert_eq!(channel_2_peak(&mut apu), 0.0);
+        apu.set_soloed(Channel::Square2, true);
+        assert!(channel_2_peak(&mut apu) > 0.1);
+    }
+
+    #[test]
+    fn channel_info_decodes_registers() {
+        let mut apu = powered();
+        apu.writeb(NR51, 0x20);
+        apu.writeb(NR22, 0xF3);
+        apu.writeb(NR21, 0x80);
+        apu.writeb(NR23, 0x00);
+        apu.writeb(NR24, 0xC7); // Trigger with length, frequency 0x700
+
+        let info = apu.channel_info(Channel::Square2);
+        assert!(info.enabled);
+        assert!(info.left && !info.right);
+        assert_eq!(info.volume, 15);
+        assert_eq!(info.duty, Some(2));
+        assert_eq!(info.envelope.as_ref().map(|e| e.period), Some(3));
+        assert_eq!(info.frequency, 131072.0 / 256.0);
+        assert_eq!(info.length, Some(64));
+        assert_eq!(
+            info.to_string(),
+            "on      L- vol 15 duty 50% env 15-3 512.0 Hz length 64"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::gameboy::GameBoy;
+
+// Keeps battery backed cartridge RAM in a .sav file next to the ROM
+pub struct BatterySave {
+    path: PathBuf,
+    // What is currently on disk, to only write when RAM changed
+    saved: Vec<u8>,
+}
+
+impl BatterySave {
+    pub fn for_rom(rom_path: &Path) -> Self {
+        BatterySave {
+            path: rom_path.with_extension("sav"),
+            saved: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // A missing save file is not an error, the game starts from scratch
+    pub fn load(&mut self, gameboy: &mut GameBoy) -> std::io::Result<()> {
+        if gameboy.battery_ram().is_none() {
+            return Ok(());
+        }
+
+        match std::fs::read(&self.path) {
+            Ok(data) => gameboy.load_battery_save(&data),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        self.saved = gameboy.battery_ram().unwrap_or_default().to_vec();
+        Ok(())
+    }
+
+    // Writes the save if RAM changed since the last flush. The RTC
+    // keeps its own timestamp so it does not need to be written as often.
+    pub fn flush(&mut self, gameboy: &mut GameBoy) -> std::io::Result<()> {
+        match gameboy.battery_ram() {
+            Some(ram) if ram != self.saved.as_slice() => self.save(gameboy),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes the save unconditionally, to call on exit
+    pub fn save(&mut self, gameboy: &mut GameBoy) -> std::io::Result<()> {
+        let save = match gameboy.battery_save() {
+            Some(save) => save,
+            None => return Ok(()),
+        };
+
+        write_atomic(&self.path, &save)?;
+        self.saved = gameboy.battery_ram().unwrap_or_default().to_vec();
+        Ok(())
+    }
+}
+
+// Writes to a temporary file then renames it over the destination,
+// so a crash while saving leaves the previous save intact
+pub fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,360 @@
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use std::time::Instant;
+
+use gb_rs::cartridge::Cartridge;
+use gb_rs::gbs::GbsPlayer;
+use gb_rs::screenshot;
+use gb_rs::serial::StdoutDevice;
+use gb_rs::wav::WavWriter;
+use gb_rs::GameBoy;
+use gb_rs::JoypadInput;
+use gb_rs::Model;
+
+const USAGE: &str = "Usage: gb-rs-headless <rom> [--frames N] [--timeout SECONDS] \
+[--input SCRIPT] [--screenshot PNG] [--serial] [--break-on-lockup] [--boot-rom PATH] [--model NAME] [--wav PATH] [--track N] [--compare PNG [--diff PNG]]";
+
+// Joypad script: one event per line, `<frame> <button> <press|release>`
+// Empty lines and lines starting with '#' are ignored
+struct InputEvent {
+    frame: u64,
+    input: JoypadInput,
+    pressed: bool,
+}
+
+struct Options {
+    rom: String,
+    frames: u64,
+    timeout: Option<Duration>,
+    input: Option<String>,
+    screenshot: Option<PathBuf>,
+    serial: bool,
+    break_on_lockup: bool,
+    boot_rom: Option<String>,
+    model: Option<Model>,
+    wav: Option<PathBuf>,
+    // Song of a GBS file, from 1
+    track: Option<u8>,
+    compare: Option<PathBuf>,
+    diff: Option<PathBuf>,
+}
+
+fn parse_button(name: &str) -> Option<JoypadInput> {
+    match name.to_lowercase().as_str() {
+        "right" => Some(JoypadInput::Right),
+        "left" => Some(JoypadInput::Left),
+        "up" => Some(JoypadInput::Up),
+        "down" => Some(JoypadInput::Down),
+        "a" => Some(JoypadInput::A),
+        "b" => Some(JoypadInput::B),
+        "select" => Some(JoypadInput::Select),
+        "start" => Some(JoypadInput::Start),
+        _ => None,
+    }
+}
+
+fn parse_script(script: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let event = match fields[..] {
+            [frame, button, action] => {
+                let frame = frame.parse().ok();
+                let input = parse_button(button);
+                let pressed = match action {
+                    "press" => Some(true),
+                    "release" => Some(false),
+                    _ => None,
+                };
+                match (frame, input, pressed) {
+                    (Some(frame), Some(input), Some(pressed)) => Some(InputEvent {
+                        frame,
+                        input,
+                        pressed,
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match event {
+            Some(event) => events.push(event),
+            None => return Err(format!("Invalid input script line {}: {}", number + 1, line)),
+        }
+    }
+
+    events.sort_by_key(|event| event.frame);
+    Ok(events)
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        frames: 60,
+        timeout: None,
+        input: None,
+        screenshot: None,
+        serial: false,
+        break_on_lockup: false,
+        boot_rom: None,
+        model: None,
+        wav: None,
+        track: None,
+        compare: None,
+        diff: None,
+    };
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+
+        match arg.as_str() {
+            "--frames" => {
+                options.frames = value()?
+                    .parse()
+                    .map_err(|_| "Invalid frame count".to_string())?
+            }
+            "--timeout" => {
+                let seconds: f64 = value()?
+                    .parse()
+                    .map_err(|_| "Invalid timeout".to_string())?;
+                options.timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--input" => options.input = Some(value()?.clone()),
+            "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+            "--serial" => options.serial = true,
+            "--break-on-lockup" => options.break_on_lockup = true,
+            "--boot-rom" => options.boot_rom = Some(value()?.clone()),
+            "--model" => options.model = Some(value()?.parse()?),
+            "--wav" => options.wav = Some(PathBuf::from(value()?)),
+            "--track" => {
+                let track: u8 = value()?
+                    .parse()
+                    .map_err(|_| "Invalid track".to_string())?;
+                if track == 0 {
+                    return Err("Tracks are numbered from 1".to_string());
+                }
+                options.track = Some(track);
+            }
+            "--compare" => options.compare = Some(PathBuf::from(value()?)),
+            "--diff" => options.diff = Some(PathBuf::from(value()?)),
+            _ if options.rom.is_empty() && !arg.starts_with("--") => options.rom = arg.clone(),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err("Missing ROM path".to_string());
+    }
+    // The boot ROM decides which model runs
+    if options.boot_rom.is_some() && options.model.is_some() {
+        return Err("--boot-rom and --model cannot be used together".to_string());
+    }
+    if options.diff.is_some() && options.compare.is_none() {
+        return Err("--diff requires --compare".to_string());
+    }
+
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let rom = std::fs::read(&options.rom).map_err(|e| format!("Cannot read rom: {}", e))?;
+    if rom.starts_with(b"GBS") {
+        return run_gbs(&options, &rom);
+    }
+    if options.track.is_some() {
+        return Err("--track only applies to GBS files".to_string());
+    }
+    let events = match &options.input {
+        Some(path) => {
+            let script = std::fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read input script: {}", e))?;
+            parse_script(&script)?
+        }
+        None => Vec::new(),
+    };
+
+    let cartridge = Cartridge::from_bytes(rom).map_err(|e| e.to_string())?;
+    let mut gameboy = match &options.boot_rom {
+        Some(path) => {
+            let boot_rom =
+                std::fs::read(path).map_err(|e| format!("Cannot read boot rom: {}", e))?;
+            GameBoy::with_boot_rom(cartridge, boot_rom).map_err(|e| e.to_string())?
+        }
+        None => GameBoy::with_model(cartridge, options.model.unwrap_or_default()),
+    };
+    gameboy.set_break_on_lockup(options.break_on_lockup);
+    if options.serial {
+        gameboy.set_serial_device(Box::new(StdoutDevice));
+    }
+    let mut wav = match &options.wav {
+        Some(path) => Some(create_wav(path, gameboy.sample_rate())?),
+        None => {
+            gameboy.set_sample_rate(0);
+            None
+        }
+    };
+    let mut events = events.iter().peekable();
+    let start = Instant::now();
+
+    let mut frame = 0;
+    while frame < options.frames {
+        while let Some(event) = events.next_if(|event| event.frame <= frame) {
+            gameboy.set_button(event.input, event.pressed);
+        }
+
+        gameboy.run_frame().map_err(|e| e.to_string())?;
+        frame += 1;
+
+        if let Some(wav) = &mut wav {
+            wav.write_samples(&gameboy.take_samples())
+                .map_err(|e| format!("Cannot write audio: {}", e))?;
+        }
+
+        if let Some(timeout) = options.timeout {
+            if start.elapsed() >= timeout {
+                eprintln!("Timeout reached after {} frames", frame);
+                break;
+            }
+        }
+    }
+
+    if let Some(wav) = wav {
+        wav.finish()
+            .map_err(|e| format!("Cannot write audio: {}", e))?;
+    }
+
+    if let Some(lockup) = gameboy.lockup() {
+        eprintln!(
+            "CPU locked up on illegal opcode {:#04x} at {:#06x}",
+            lockup.opcode, lockup.pc
+        );
+    }
+
+    if let Some(path) = &options.screenshot {
+        screenshot::save_png(gameboy.framebuffer(), path)
+            .map_err(|e| format!("Cannot write screenshot: {}", e))?;
+    }
+
+    println!("{:016x}", screenshot::framebuffer_hash(gameboy.framebuffer()));
+
+    if let Some(reference) = &options.compare {
+        compare(gameboy.framebuffer(), reference, options.diff.as_deref())?;
+    }
+
+    Ok(())
+}
+
+fn create_wav(path: &Path, sample_rate: u32) -> Result<WavWriter<BufWriter<File>>, String> {
+    WavWriter::create(path, sample_rate)
+        .map_err(|e| format!("Cannot create {}: {}", path.display(), e))
+}
+
+// GBS files have no screen, the sound can be recorded with --wav
+fn run_gbs(options: &Options, data: &[u8]) -> Result<(), String> {
+    // GBS files have no screen, joypad or serial, and always play on a DMG
+    let unsupported = [
+        ("--input", options.input.is_some()),
+        ("--serial", options.serial),
+        ("--model", options.model.is_some()),
+        ("--boot-rom", options.boot_rom.is_some()),
+        ("--screenshot", options.screenshot.is_some()),
+        ("--compare", options.compare.is_some()),
+    ];
+    if let Some((flag, _)) = unsupported.iter().find(|(_, given)| *given) {
+        return Err(format!("{} cannot be used with GBS files", flag));
+    }
+
+    let mut player = GbsPlayer::new(data).map_err(|e| e.to_string())?;
+    if let Some(track) = options.track {
+        player.start_song(track - 1).map_err(|e| e.to_string())?;
+    }
+    let mut wav = match &options.wav {
+        Some(path) => Some(create_wav(path, player.sample_rate())?),
+        None => {
+            player.set_sample_rate(0);
+            None
+        }
+    };
+
+    let header = player.header();
+    println!(
+        "{} - track {}/{}",
+        header.title,
+        player.song() + 1,
+        header.song_count
+    );
+
+    let start = Instant::now();
+    for frame in 1..=options.frames {
+        player.run_frame().map_err(|e| e.to_string())?;
+
+        if let Some(wav) = &mut wav {
+            wav.write_samples(&player.take_samples())
+                .map_err(|e| format!("Cannot write audio: {}", e))?;
+        }
+
+        if let Some(timeout) = options.timeout {
+            if start.elapsed() >= timeout {
+                eprintln!("Timeout reached after {} frames", frame);
+                break;
+            }
+        }
+    }
+
+    if let Some(wav) = wav {
+        wav.finish()
+            .map_err(|e| format!("Cannot write audio: {}", e))?;
+    }
+    Ok(())
+}
+
+fn compare(screen: &screenshot::Screen, reference: &Path, diff: Option<&Path>) -> Result<(), String> {
+    let actual = screenshot::to_shades(screen);
+    let expected = screenshot::load_png_shades(reference)
+        .map_err(|e| format!("Cannot read reference: {}", e))?;
+
+    let differences = screenshot::count_differences(&actual, &expected);
+    if differences == 0 {
+        return Ok(());
+    }
+
+    if let Some(path) = diff {
+        screenshot::save_diff_png(&actual, &expected, path)
+            .map_err(|e| format!("Cannot write diff: {}", e))?;
+    }
+
+    Err(format!("{} pixels differ from {}", differences, reference.display()))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
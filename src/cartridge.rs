@@ -1,36 +1,141 @@
+use crate::error::EmuError;
+use crate::header::CartridgeHeader;
+use crate::header::MbcKind;
 use crate::mbc0::NoMbc;
 use crate::mbc1::Mbc1;
+use crate::mbc2::Mbc2;
 use crate::mbc3::Mbc3;
+use crate::mbc5::Mbc5;
+use crate::rtc::Rtc;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::fnv1a;
 
-const MBC_REGISTER: u16 = 0x147;
 pub const ROM_BANK_SIZE: u16 = 0x4000;
 pub const RAM_BANK_SIZE: u16 = 0x2000;
 
-pub trait Mbc {
+// Nothing drives the bus past the end of the ROM
+pub(crate) fn read_rom(rom: &[u8], index: usize) -> u8 {
+    *rom.get(index).unwrap_or(&0xFF)
+}
+
+// Banking registers and RAM are part of the save state
+pub trait Mbc: Stateful {
     fn readb(&self, addr: u16) -> u8;
     fn writeb(&mut self, addr: u16, value: u8);
+    // External RAM, kept on disk when the cartridge has a battery
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    // State of the rumble motor, on cartridges having one
+    fn is_rumbling(&self) -> bool {
+        false
+    }
 }
 
 pub struct Cartridge {
     mbc: Box<dyn Mbc>,
+    pub header: CartridgeHeader,
+    // Identifies the ROM in save states
+    pub rom_hash: u64,
+    pub has_battery: bool,
 }
 
 impl Cartridge {
-    pub fn new(filename: &str) -> Self {
-        let rom = std::fs::read(filename).expect("Cannot read rom");
-
-        let mbc_type = rom
-            .get(MBC_REGISTER as usize)
-            .expect("Could not read MBC register");
-
-        let mbc: Box<dyn Mbc> = match mbc_type {
-            0 => NoMbc::new(rom),
-            1 | 2 | 3 => Mbc1::new(rom),
-            0x13 => Mbc3::new(rom),
-            _ => panic!("Unsupported MBC"),
+    pub fn new(filename: &str) -> Result<Self, EmuError> {
+        let rom = std::fs::read(filename)?;
+        Cartridge::from_bytes(rom)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, EmuError> {
+        let rom_hash = fnv1a(&rom);
+        let header = CartridgeHeader::parse(&rom)?;
+        let features = header
+            .features()
+            .ok_or(EmuError::UnknownCartridgeType(header.cartridge_type))?;
+        let ram_size = if features.has_ram {
+            header.ram_size()
+        } else {
+            0
         };
 
-        Cartridge { mbc }
+        let mbc: Box<dyn Mbc> = match features.mbc {
+            MbcKind::None => NoMbc::new(rom, ram_size),
+            MbcKind::Mbc1 => Mbc1::new(rom, ram_size),
+            MbcKind::Mbc2 => Mbc2::new(rom),
+            MbcKind::Mbc3 => Mbc3::new(rom, ram_size, features.has_rtc),
+            MbcKind::Mbc5 => Mbc5::new(rom, ram_size, features.has_rumble),
+            mbc => return Err(EmuError::UnsupportedCartridge(mbc)),
+        };
+
+        Ok(Cartridge {
+            mbc,
+            header,
+            rom_hash,
+            has_battery: features.has_battery,
+        })
+    }
+
+    // Cartridges made up by the emulator, such as the one playing GBS files
+    pub(crate) fn from_mbc(mbc: Box<dyn Mbc>, header: CartridgeHeader, rom_hash: u64) -> Self {
+        Cartridge {
+            mbc,
+            header,
+            rom_hash,
+            has_battery: false,
+        }
+    }
+
+    pub fn update(&mut self, cycles: u32) {
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.update(cycles);
+        }
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.mbc.is_rumbling()
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.mbc.rtc_mut()
+    }
+
+    // RAM to persist, None if it is lost on power off
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        match self.has_battery {
+            true => Some(self.mbc.ram()),
+            false => None,
+        }
+    }
+
+    // Content of the .sav file: RAM followed by the RTC if any
+    pub fn battery_save(&mut self) -> Option<Vec<u8>> {
+        if !self.has_battery {
+            return None;
+        }
+
+        let mut save = self.mbc.ram().to_vec();
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            save.extend_from_slice(&rtc.save_footer());
+        }
+        Some(save)
+    }
+
+    // Restores RAM and RTC from a .sav file
+    pub fn load_battery_save(&mut self, data: &[u8]) {
+        let ram = self.mbc.ram_mut();
+        let len = ram.len().min(data.len());
+        ram[..len].copy_from_slice(&data[..len]);
+
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.load_footer(&data[len..]);
+        }
     }
 
     pub fn readb(&self, addr: u16) -> u8 {
@@ -41,3 +146,13 @@ impl Cartridge {
         self.mbc.writeb(addr, value);
     }
 }
+
+impl Stateful for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mbc.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mbc.load_state(state)
+    }
+}
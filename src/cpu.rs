@@ -1,5 +1,9 @@
 use crate::registers::Registers;
 use crate::mmu::Mmu;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 use crate::utils::to_u8;
 use crate::utils::to_u16;
 use crate::utils::Bits;
@@ -8,63 +12,118 @@ use crate::utils::Bits;
 const V_BLANK_ROUTINE: u16 = 0x40;
 const STAT_ROUTINE: u16 = 0x48;
 const TIMER_ROUTINE: u16 = 0x50;
+const SERIAL_ROUTINE: u16 = 0x58;
 const JOYPAD_ROUTINE: u16 = 0x60;
 
 // Interupt bit
 pub const V_BLANK_INTERUPT: u8 = 0;
 pub const STAT_INTERUPT: u8 = 1;
 pub const TIMER_INTERUPT: u8 = 2;
+pub const SERIAL_INTERUPT: u8 = 3;
 pub const JOYPAD_INTERUPT: u8 = 4;
 
+// Where the CPU hung after fetching an illegal opcode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lockup {
+    pub pc: u16,
+    pub opcode: u8,
+}
 
 pub struct Cpu {
     reg: Registers,
     ime: bool,
     halted: bool,
+    // EI enables interupts after the next instruction
+    ime_scheduled: bool,
+    // Next opcode fetch does not increment PC
+    halt_bug: bool,
+    // Stops fetching for good, only a reset recovers
+    lockup: Option<Lockup>,
+    // Cycles the other components ran for since the instruction started
+    cycles: u32,
 }
 
-impl Default for Cpu {
-    fn default() -> Self {
+impl Cpu {
+    // State at power on, for running a boot ROM
+    pub fn power_on() -> Self {
         Cpu {
-            reg: Registers::default(),
-            ime: true,
+            reg: Registers::power_on(),
+            ime: false,
             halted: false,
+            ime_scheduled: false,
+            halt_bug: false,
+            lockup: None,
+            cycles: 0,
         }
     }
-}
 
-impl Cpu {
-    // TODO: better jr
+    pub fn registers(&self) -> &Registers {
+        &self.reg
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.reg
+    }
+
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.lockup
+    }
+
+    // Runs one instruction and returns its number of cycles. The other
+    // components are ticked on each memory access, so they see it happen
+    // at the right M-cycle of the instruction.
     pub fn run_cycle(&mut self, mmu: &mut Mmu) -> u32 {
-        // In this implementation, the Cpu will give the number of cycle
-        // to run on other components
+        self.cycles = 0;
+
+        if self.ime_scheduled {
+            self.ime_scheduled = false;
+            self.ime = true;
+        }
 
-        if self.halted {
+        // Other components keep running while the CPU does nothing
+        if self.halted || self.lockup.is_some() || mmu.is_stopped() {
+            self.tick(mmu);
             return 4;
         }
 
+        let cycles = self.execute(mmu);
+        debug_assert!(self.cycles <= cycles, "Instruction took too many cycles");
+
+        // Internal cycles after the last access
+        while self.cycles < cycles {
+            self.tick(mmu);
+        }
+        cycles
+    }
+
+    // TODO: better jr
+    fn execute(&mut self, mmu: &mut Mmu) -> u32 {
         let opcode = self.readb(mmu);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        }
 
         match opcode {
             0x00 => 4, // NOP
             0x01 => { let w = self.readw(mmu); self.reg.set_bc(w); 12 }, // LD BC, n16
-            0x02 => { mmu.writeb(self.reg.bc(), self.reg.a); 8 }, // LD (BC), A
+            0x02 => { self.write(mmu, self.reg.bc(), self.reg.a); 8 }, // LD (BC), A
             0x03 => { let bc = self.reg.bc(); self.reg.set_bc(bc.wrapping_add(1)); 8 }, // INC BC
             0x04 => { self.reg.b = self.inc(self.reg.b); 4 }, // INC B
             0x05 => { self.reg.b = self.dec(self.reg.b); 4 }, // DEC B
             0x06 => { self.reg.b = self.readb(mmu); 8 }, // LD B, u8
             0x07 => { self.reg.a = self.rlc(self.reg.a); self.reg.set_z(false); 4 }, // RLCA
-            0x08 => { let addr = self.readw(mmu); mmu.writew(addr, self.reg.sp); 20 }, // LD (u16), SP
+            0x08 => { let addr = self.readw(mmu); let (msb, lsb) = to_u8(self.reg.sp); self.write(mmu, addr, lsb); self.write(mmu, addr.wrapping_add(1), msb); 20 }, // LD (u16), SP
             0x09 => { self.add16(self.reg.bc()); 8 }, // ADD HL, BC
-            0x0a => { let bc = self.reg.bc(); self.reg.a = mmu.readb(bc); 8 }, // LD A, (BC)
+            0x0a => { let bc = self.reg.bc(); self.reg.a = self.read(mmu, bc); 8 }, // LD A, (BC)
             0x0b => { let bc = self.reg.bc(); self.reg.set_bc(bc.wrapping_sub(1)); 8 }, // DEC BC
             0x0c => { self.reg.c = self.inc(self.reg.c); 4 }, // INC C
             0x0d => { self.reg.c = self.dec(self.reg.c); 4 }, // DEC C
             0x0e => { self.reg.c = self.readb(mmu); 8 }, // LD C, u8
             0x0f => { self.reg.a = self.rrc(self.reg.a); self.reg.set_z(false); 4 }, // RRCA
-            0x10 => 4, // STOP
+            0x10 => { self.reg.pc = self.reg.pc.wrapping_add(1); mmu.stop(); 4 }, // STOP
             0x11 => { let w = self.readw(mmu); self.reg.set_de(w); 12 }, // LD DE, n16
-            0x12 => { mmu.writeb(self.reg.de(), self.reg.a); 8 }, // LD (DE), A
+            0x12 => { self.write(mmu, self.reg.de(), self.reg.a); 8 }, // LD (DE), A
             0x13 => { let de = self.reg.de(); self.reg.set_de(de.wrapping_add(1)); 8 }, // INC DE
             0x14 => { self.reg.d = self.inc(self.reg.d); 4 }, // INC D
             0x15 => { self.reg.d = self.dec(self.reg.d); 4 }, // DEC D
@@ -72,7 +131,7 @@ impl Cpu {
             0x17 => { self.reg.a = self.rl(self.reg.a); self.reg.set_z(false); 4 }, // RLA
             0x18 => { let delta = self.readb(mmu); self.jr(delta as i8); 12 }, // JR i8
             0x19 => { self.add16(self.reg.de()); 8 }, // ADD HL, DE
-            0x1a => { let de = self.reg.de(); self.reg.a = mmu.readb(de); 8 }, // LD A, (DE)
+            0x1a => { let de = self.reg.de(); self.reg.a = self.read(mmu, de); 8 }, // LD A, (DE)
             0x1b => { let de = self.reg.de(); self.reg.set_de(de.wrapping_sub(1)); 8 }, // DEC DE
             0x1c => { self.reg.e = self.inc(self.reg.e); 4 }, // INC E
             0x1d => { self.reg.e = self.dec(self.reg.e); 4 }, // DEC E
@@ -80,7 +139,7 @@ impl Cpu {
             0x1f => { self.reg.a = self.rr(self.reg.a); self.reg.set_z(false); 4 }, // RRA
             0x20 => { let delta = self.readb(mmu); if self.reg.get_z() { 8 } else { self.jr(delta as i8); 12 } } // JR NZ, i8
             0x21 => { let w = self.readw(mmu); self.reg.set_hl(w); 12 }, // LD HL, n16
-            0x22 => { mmu.writeb(self.reg.hl(), self.reg.a); self.inc_hl(); 8 }, // LD (HL+), A
+            0x22 => { self.write(mmu, self.reg.hl(), self.reg.a); self.inc_hl(); 8 }, // LD (HL+), A
             0x23 => { self.inc_hl(); 8 }, // INC HL
             0x24 => { self.reg.h = self.inc(self.reg.h); 4 }, // INC H
             0x25 => { self.reg.h = self.dec(self.reg.h); 4 }, // DEC H
@@ -88,7 +147,7 @@ impl Cpu {
             0x27 => { self.daa(); 4 },
             0x28 => { let delta = self.readb(mmu); if self.reg.get_z() { self.jr(delta as i8); 12 } else { 8 } } // JR Z, i8
             0x29 => { self.add16(self.reg.hl()); 8 }, // ADD HL, HL
-            0x2a => { let hl = self.reg.hl(); self.inc_hl(); self.reg.a = mmu.readb(hl); 8 }, // LD A, (HL+)
+            0x2a => { let hl = self.reg.hl(); self.inc_hl(); self.reg.a = self.read(mmu, hl); 8 }, // LD A, (HL+)
             0x2b => { self.dec_hl(); 8 }, // DEC HL
             0x2c => { self.reg.l = self.inc(self.reg.l); 4 }, // INC L
             0x2d => { self.reg.l = self.dec(self.reg.l); 4 }, // DEC L
@@ -96,15 +155,15 @@ impl Cpu {
             0x2f => { self.cpl(); 4 }, // CPL
             0x30 => { let delta = self.readb(mmu); if self.reg.get_c() { 8 } else { self.jr(delta as i8); 12 } } // JR NC, i8
             0x31 => { self.reg.sp = self.readw(mmu); 12 }, // LD SP, n16
-            0x32 => { mmu.writeb(self.reg.hl(), self.reg.a); self.dec_hl(); 8 }, // LD (HL-), A
+            0x32 => { self.write(mmu, self.reg.hl(), self.reg.a); self.dec_hl(); 8 }, // LD (HL-), A
             0x33 => { self.reg.sp = self.reg.sp.wrapping_add(1); 8 }, // INC SP
             0x34 => { let hl = self.reg.hl(); self.inc_at(mmu, hl); 12 }, // INC (HL)
             0x35 => { let hl = self.reg.hl(); self.dec_at(mmu, hl); 12 }, // DEC (HL)
-            0x36 => { let val = self.readb(mmu); mmu.writeb(self.reg.hl(), val); 12 }, // LD (HL), u8
+            0x36 => { let val = self.readb(mmu); self.write(mmu, self.reg.hl(), val); 12 }, // LD (HL), u8
             0x37 => { self.scf(); 4 }, // SCF
             0x38 => { let delta = self.readb(mmu); if self.reg.get_c() { self.jr(delta as i8); 12 } else { 8 } }, // JR C, i8
             0x39 => { self.add16(self.reg.sp); 8 }, // ADD HL, SP
-            0x3a => { let hl = self.reg.hl(); self.dec_hl(); self.reg.a = mmu.readb(hl); 8 }, // LD A, (HL-)
+            0x3a => { let hl = self.reg.hl(); self.dec_hl(); self.reg.a = self.read(mmu, hl); 8 }, // LD A, (HL-)
             0x3b => { self.reg.sp = self.reg.sp.wrapping_sub(1); 8 }, // DEC SP
             0x3c => { self.reg.a = self.inc(self.reg.a); 4 }, // INC A
             0x3d => { self.reg.a = self.dec(self.reg.a); 4 }, // DEC A
@@ -117,7 +176,7 @@ impl Cpu {
             0x43 => { self.reg.b = self.reg.e; 4 }, // LD B, E
             0x44 => { self.reg.b = self.reg.h; 4 }, // LD B, H
             0x45 => { self.reg.b = self.reg.l; 4 }, // LD B, L
-            0x46 => { self.reg.b = mmu.readb(self.reg.hl()); 8 }, // LD B, (HL)
+            0x46 => { self.reg.b = self.read(mmu, self.reg.hl()); 8 }, // LD B, (HL)
             0x47 => { self.reg.b = self.reg.a; 4 }, // LD B, A
             0x48 => { self.reg.c = self.reg.b; 4 }, // LD C, B
             0x49 => { 4 }, // LD C, C
@@ -125,7 +184,7 @@ impl Cpu {
             0x4b => { self.reg.c = self.reg.e; 4 }, // LD C, E
             0x4c => { self.reg.c = self.reg.h; 4 }, // LD C, H
             0x4d => { self.reg.c = self.reg.l; 4 }, // LD C, L
-            0x4e => { self.reg.c = mmu.readb(self.reg.hl()); 8 }, // LD C, (HL)
+            0x4e => { self.reg.c = self.read(mmu, self.reg.hl()); 8 }, // LD C, (HL)
             0x4f => { self.reg.c = self.reg.a; 4 }, // LD C, A
             0x50 => { self.reg.d = self.reg.b; 4 }, // LD D, B
             0x51 => { self.reg.d = self.reg.c; 4 }, // LD D, C
@@ -133,7 +192,7 @@ impl Cpu {
             0x53 => { self.reg.d = self.reg.e; 4 }, // LD D, E
             0x54 => { self.reg.d = self.reg.h; 4 }, // LD D, H
             0x55 => { self.reg.d = self.reg.l; 4 }, // LD D, L
-            0x56 => { self.reg.d = mmu.readb(self.reg.hl()); 8 }, // LD D, (HL)
+            0x56 => { self.reg.d = self.read(mmu, self.reg.hl()); 8 }, // LD D, (HL)
             0x57 => { self.reg.d = self.reg.a; 4 }, // LD D, A
             0x58 => { self.reg.e = self.reg.b; 4 }, // LD E, B
             0x59 => { self.reg.e = self.reg.c; 4 }, // LD E, C
@@ -141,7 +200,7 @@ impl Cpu {
             0x5b => { 4 }, // LD E, E
             0x5c => { self.reg.e = self.reg.h; 4 }, // LD E, H
             0x5d => { self.reg.e = self.reg.l; 4 }, // LD E, L
-            0x5e => { self.reg.e = mmu.readb(self.reg.hl()); 8 }, // LD E, (HL)
+            0x5e => { self.reg.e = self.read(mmu, self.reg.hl()); 8 }, // LD E, (HL)
             0x5f => { self.reg.e = self.reg.a; 4 }, // LD E, A
             0x60 => { self.reg.h = self.reg.b; 4 }, // LD H, B
             0x61 => { self.reg.h = self.reg.c; 4 }, // LD H, C
@@ -149,7 +208,7 @@ impl Cpu {
             0x63 => { self.reg.h = self.reg.e; 4 }, // LD H, E
             0x64 => { 4 }, // LD H, H
             0x65 => { self.reg.h = self.reg.l; 4 }, // LD H, L
-            0x66 => { self.reg.h = mmu.readb(self.reg.hl()); 8 }, // LD H, (HL)
+            0x66 => { self.reg.h = self.read(mmu, self.reg.hl()); 8 }, // LD H, (HL)
             0x67 => { self.reg.h = self.reg.a; 4 }, // LD H, A
             0x68 => { self.reg.l = self.reg.b; 4 }, // LD L, B
             0x69 => { self.reg.l = self.reg.c; 4 }, // LD L, C
@@ -157,23 +216,23 @@ impl Cpu {
             0x6b => { self.reg.l = self.reg.e; 4 }, // LD L, E
             0x6c => { self.reg.l = self.reg.h; 4 }, // LD L, H
             0x6d => { 4 }, // LD L, L
-            0x6e => { self.reg.l = mmu.readb(self.reg.hl()); 8 }, // LD L, (HL)
+            0x6e => { self.reg.l = self.read(mmu, self.reg.hl()); 8 }, // LD L, (HL)
             0x6f => { self.reg.l = self.reg.a; 4 }, // LD L, A
-            0x70 => { mmu.writeb(self.reg.hl(), self.reg.b); 8 }, // LD (HL), B
-            0x71 => { mmu.writeb(self.reg.hl(), self.reg.c); 8 }, // LD (HL), C
-            0x72 => { mmu.writeb(self.reg.hl(), self.reg.d); 8 }, // LD (HL), D
-            0x73 => { mmu.writeb(self.reg.hl(), self.reg.e); 8 }, // LD (HL), E
-            0x74 => { mmu.writeb(self.reg.hl(), self.reg.h); 8 }, // LD (HL), H
-            0x75 => { mmu.writeb(self.reg.hl(), self.reg.l); 8 }, // LD (HL), L
-            0x76 => { self.halted = true; 4 }, // HALT
-            0x77 => { mmu.writeb(self.reg.hl(), self.reg.a); 8 },
+            0x70 => { self.write(mmu, self.reg.hl(), self.reg.b); 8 }, // LD (HL), B
+            0x71 => { self.write(mmu, self.reg.hl(), self.reg.c); 8 }, // LD (HL), C
+            0x72 => { self.write(mmu, self.reg.hl(), self.reg.d); 8 }, // LD (HL), D
+            0x73 => { self.write(mmu, self.reg.hl(), self.reg.e); 8 }, // LD (HL), E
+            0x74 => { self.write(mmu, self.reg.hl(), self.reg.h); 8 }, // LD (HL), H
+            0x75 => { self.write(mmu, self.reg.hl(), self.reg.l); 8 }, // LD (HL), L
+            0x76 => { self.halt(mmu); 4 }, // HALT
+            0x77 => { self.write(mmu, self.reg.hl(), self.reg.a); 8 },
             0x78 => { self.reg.a = self.reg.b; 4 }, // LD A, B
             0x79 => { self.reg.a = self.reg.c; 4 }, // LD A, C
             0x7a => { self.reg.a = self.reg.d; 4 }, // LD A, D
             0x7b => { self.reg.a = self.reg.e; 4 }, // LD A, E
             0x7c => { self.reg.a = self.reg.h; 4 }, // LD A, H
             0x7d => { self.reg.a = self.reg.l; 4 }, // LD A, L
-            0x7e => { self.reg.a = mmu.readb(self.reg.hl()); 8 }, // LD A, (HL)
+            0x7e => { self.reg.a = self.read(mmu, self.reg.hl()); 8 }, // LD A, (HL)
             0x7f => { 4 }, // LD A, A
 
             0x80 | 0x88 => { self.add(self.reg.b, opcode == 0x88); 4 }, // ADD A, B or ADC A, B
@@ -182,7 +241,7 @@ impl Cpu {
             0x83 | 0x8b => { self.add(self.reg.e, opcode == 0x8b); 4 }, // ADD A, E or ADC A, E
             0x84 | 0x8c => { self.add(self.reg.h, opcode == 0x8c); 4 }, // ADD A, H or ADC A, H
             0x85 | 0x8d => { self.add(self.reg.l, opcode == 0x8d); 4 }, // ADD A, L or ADC A, L
-            0x86 | 0x8e => { let val = mmu.readb(self.reg.hl()); self.add(val, opcode == 0x8e); 8 }, // ADD A, (HL) or ADC A, (HL)
+            0x86 | 0x8e => { let val = self.read(mmu, self.reg.hl()); self.add(val, opcode == 0x8e); 8 }, // ADD A, (HL) or ADC A, (HL)
             0x87 | 0x8f => { self.add(self.reg.a, opcode == 0x8f); 4 }, // ADD A, A or SBC A, A
 
             0x90 | 0x98 => { self.sub(self.reg.b, opcode == 0x98); 4 }, // SUB A, B or SBC A, B
@@ -191,7 +250,7 @@ impl Cpu {
             0x93 | 0x9b => { self.sub(self.reg.e, opcode == 0x9b); 4 }, // SUB A, E or SBC A, E
             0x94 | 0x9c => { self.sub(self.reg.h, opcode == 0x9c); 4 }, // SUB A, H or SBC A, H
             0x95 | 0x9d => { self.sub(self.reg.l, opcode == 0x9d); 4 }, // SUB A, L or SBC A, L
-            0x96 | 0x9e => { let val = mmu.readb(self.reg.hl()); self.sub(val, opcode == 0x9e); 8 }, // SUB A, (HL) or SBC A, (HL)
+            0x96 | 0x9e => { let val = self.read(mmu, self.reg.hl()); self.sub(val, opcode == 0x9e); 8 }, // SUB A, (HL) or SBC A, (HL)
             0x97 | 0x9f => { self.sub(self.reg.a, opcode == 0x9f); 4 }, // SUB A, A or SBC A, A
 
             0xa0 => { self.and(self.reg.b); 4 }, // AND A, B
@@ -200,7 +259,7 @@ impl Cpu {
             0xa3 => { self.and(self.reg.e); 4 }, // AND A, E
             0xa4 => { self.and(self.reg.h); 4 }, // AND A, H
             0xa5 => { self.and(self.reg.l); 4 }, // AND A, L
-            0xa6 => { let val = mmu.readb(self.reg.hl()); self.and(val); 8 }, // AND A, (HL)
+            0xa6 => { let val = self.read(mmu, self.reg.hl()); self.and(val); 8 }, // AND A, (HL)
             0xa7 => { self.and(self.reg.a); 4 }, // AND A, A
 
             0xa8 => { self.xor(self.reg.b); 4 }, // XOR A, B
@@ -209,7 +268,7 @@ impl Cpu {
             0xab => { self.xor(self.reg.e); 4 }, // XOR A, E
             0xac => { self.xor(self.reg.h); 4 }, // XOR A, H
             0xad => { self.xor(self.reg.l); 4 }, // XOR A, L
-            0xae => { let val = mmu.readb(self.reg.hl()); self.xor(val); 8 }, // XOR A, (HL)
+            0xae => { let val = self.read(mmu, self.reg.hl()); self.xor(val); 8 }, // XOR A, (HL)
             0xaf => { self.xor(self.reg.a); 4 }, // XOR A, A
 
             0xb0 => { self.or(self.reg.b); 4 }, // OR A, B
@@ -218,7 +277,7 @@ impl Cpu {
             0xb3 => { self.or(self.reg.e); 4 }, // OR A, E
             0xb4 => { self.or(self.reg.h); 4 }, // OR A, H
             0xb5 => { self.or(self.reg.l); 4 }, // OR A, L
-            0xb6 => { let val = mmu.readb(self.reg.hl()); self.or(val); 8 }, // OR A, (HL)
+            0xb6 => { let val = self.read(mmu, self.reg.hl()); self.or(val); 8 }, // OR A, (HL)
             0xb7 => { self.or(self.reg.a); 4 }, // OR A, A
 
             0xb8 => { self.cp(self.reg.b); 4 }, // CP A, B
@@ -227,10 +286,10 @@ impl Cpu {
             0xbb => { self.cp(self.reg.e); 4 }, // CP A, E
             0xbc => { self.cp(self.reg.h); 4 }, // CP A, H
             0xbd => { self.cp(self.reg.l); 4 }, // CP A, L
-            0xbe => { let val = mmu.readb(self.reg.hl()); self.cp(val); 8 }, // CP A, (HL)
+            0xbe => { let val = self.read(mmu, self.reg.hl()); self.cp(val); 8 }, // CP A, (HL)
             0xbf => { self.cp(self.reg.a); 4 }, // CP A, A
 
-            0xc0 => { if self.reg.get_z() { 8 } else { self.reg.pc = self.pop(mmu); 20 }}, // RET NZ
+            0xc0 => { self.tick(mmu); if self.reg.get_z() { 8 } else { self.reg.pc = self.pop(mmu); 20 }}, // RET NZ
             0xc1 => { let bc = self.pop(mmu); self.reg.set_bc(bc); 12 }, // POP BC
             0xc2 => { let addr = self.readw(mmu); if self.reg.get_z() { 12 } else { self.reg.pc = addr; 16 } }, // JP NZ, u16
             0xc3 => { self.reg.pc = self.readw(mmu); 16 }, // JP u16
@@ -238,7 +297,7 @@ impl Cpu {
             0xc5 => { self.push(mmu, self.reg.bc()); 16 }, // PUSH BC
             0xc6 | 0xce => { let val = self.readb(mmu); self.add(val, opcode == 0xce); 8 }, // ADD A, u8 or ADC A, u8
             0xc7 => { self.call(mmu, 0); 16 }, // RST 00
-            0xc8 => { if self.reg.get_z() { self.reg.pc = self.pop(mmu); 20 } else { 8 }}, // RET Z
+            0xc8 => { self.tick(mmu); if self.reg.get_z() { self.reg.pc = self.pop(mmu); 20 } else { 8 }}, // RET Z
             0xc9 => { self.reg.pc = self.pop(mmu); 16 }, // RET
             0xca => { let addr = self.readw(mmu); if self.reg.get_z() { self.reg.pc = addr; 16 } else { 12 } }, // JP Z, u16
             0xcb => { self.run_prefixed(mmu) }, // PREFIX
@@ -246,7 +305,7 @@ impl Cpu {
             0xcd => { let addr = self.readw(mmu); self.call(mmu, addr); 24 }, // CALL u16
             0xcf => { self.call(mmu, 0x08); 16 }, // RST 08
 
-            0xd0 => { if self.reg.get_c() { 8 } else { self.reg.pc = self.pop(mmu); 20 }}, // RET NC
+            0xd0 => { self.tick(mmu); if self.reg.get_c() { 8 } else { self.reg.pc = self.pop(mmu); 20 }}, // RET NC
             0xd1 => { let de = self.pop(mmu); self.reg.set_de(de); 12 }, // POP DE
 
             0xd2 => { let addr = self.readw(mmu); if self.reg.get_c() { 12 } else { self.reg.pc = addr; 16 } }, // JP NC, u16
@@ -254,38 +313,44 @@ impl Cpu {
             0xd5 => { self.push(mmu, self.reg.de()); 16 }, // PUSH DE
             0xd6 | 0xde => { let val = self.readb(mmu); self.sub(val, opcode == 0xde); 8 }, // SUB A, u8 or SBC A, u8
             0xd7 => { self.call(mmu, 0x10); 16 }, // RST 10
-            0xd8 => { if self.reg.get_c() { self.reg.pc = self.pop(mmu); 20 } else { 8 }}, // RET C
+            0xd8 => { self.tick(mmu); if self.reg.get_c() { self.reg.pc = self.pop(mmu); 20 } else { 8 }}, // RET C
             0xd9 => { self.ime = true; self.reg.pc = self.pop(mmu); 16 },
             0xda => { let addr = self.readw(mmu); if self.reg.get_c() { self.reg.pc = addr; 16 } else { 12 } }, // JP C, u16
             0xdc => { let addr = self.readw(mmu); if self.reg.get_c() { self.call(mmu, addr); 24 } else { 12 }}, // CALL C, u16
             0xdf => { self.call(mmu, 0x18); 16 }, // RST 18
 
-            0xe0 => { let addr = 0xff00 | self.readb(mmu) as u16; mmu.writeb(addr, self.reg.a); 12 }, // LD (FF00+u8), A
+            0xe0 => { let addr = 0xff00 | self.readb(mmu) as u16; self.write(mmu, addr, self.reg.a); 12 }, // LD (FF00+u8), A
             0xe1 => { let hl = self.pop(mmu); self.reg.set_hl(hl); 12 }, // POP HL
-            0xe2 => { let addr = 0xff00 | self.reg.c as u16; mmu.writeb(addr, self.reg.a); 8 }, // LD (FF00+C), A
+            0xe2 => { let addr = 0xff00 | self.reg.c as u16; self.write(mmu, addr, self.reg.a); 8 }, // LD (FF00+C), A
             0xe5 => { self.push(mmu, self.reg.hl()); 16 }, // PUSH HL
             0xe6 => { let val = self.readb(mmu); self.and(val); 8 }, // AND A, u8
             0xe7 => { self.call(mmu, 0x20); 16 }, // RST 20
             0xe8 => { let delta = self.readb(mmu); self.reg.sp = self.add_sp(delta); 16 }, // ADD SP, i8
             0xe9 => { self.reg.pc = self.reg.hl(); 4 }, // JP HL
-            0xea => { let addr = self.readw(mmu); mmu.writeb(addr, self.reg.a); 16 }, // LD (u16), A
+            0xea => { let addr = self.readw(mmu); self.write(mmu, addr, self.reg.a); 16 }, // LD (u16), A
             0xee => { let val = self.readb(mmu); self.xor(val); 8 }, // XOR A, u8
             0xef => { self.call(mmu, 0x28); 16 }, // RST 28
 
-            0xf0 => { let addr = 0xff00 | self.readb(mmu) as u16; self.reg.a = mmu.readb(addr); 12 }, // LD A, (FF00+u8)
+            0xf0 => { let addr = 0xff00 | self.readb(mmu) as u16; self.reg.a = self.read(mmu, addr); 12 }, // LD A, (FF00+u8)
             0xf1 => { let af = self.pop(mmu); self.reg.set_af(af); 12 }, // POP AF
-            0xf2 => { let addr = 0xff00 | self.reg.c as u16; self.reg.a = mmu.readb(addr); 8 }, // LD A, (FF00+C)
-            0xf3 => { self.ime = false; 4 }, // DI
+            0xf2 => { let addr = 0xff00 | self.reg.c as u16; self.reg.a = self.read(mmu, addr); 8 }, // LD A, (FF00+C)
+            0xf3 => { self.ime = false; self.ime_scheduled = false; 4 }, // DI
             0xf5 => { self.push(mmu, self.reg.af()); 16 }, // PUSH AF
             0xf6 => { let val = self.readb(mmu); self.or(val); 8 }, // OR A, u8
             0xf7 => { self.call(mmu, 0x30); 16 }, // RST 30
             0xf8 => { let delta = self.readb(mmu); let res = self.add_sp(delta); self.reg.set_hl(res); 12 }, // LD HL, SP+i8
             0xf9 => { self.reg.sp = self.reg.hl(); 8 }, // LD SP, HL
-            0xfa => { let addr = self.readw(mmu); self.reg.a = mmu.readb(addr); 16 }, // LD A, (u16)
-            0xfb => { self.ime = true; 4 }, // EI
+            0xfa => { let addr = self.readw(mmu); self.reg.a = self.read(mmu, addr); 16 }, // LD A, (u16)
+            0xfb => { self.ime_scheduled = true; 4 }, // EI
             0xfe => { let val = self.readb(mmu); self.cp(val); 8 }, // CP A, u8
             0xff => { self.call(mmu, 0x38); 16 }, // RST 38
-            _ => panic!("Unknown opcode {:#04x} at {:#04x}.", opcode, self.reg.pc - 1)
+            // 0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd
+            _ => {
+                let pc = self.reg.pc.wrapping_sub(1);
+                self.reg.pc = pc;
+                self.lockup = Some(Lockup { pc, opcode });
+                4
+            }
         }
     }
 
@@ -299,7 +364,7 @@ impl Cpu {
             0x03 => { self.reg.e = self.rlc(self.reg.e); 8 }, // RLC E
             0x04 => { self.reg.h = self.rlc(self.reg.h); 8 }, // RLC H
             0x05 => { self.reg.l = self.rlc(self.reg.l); 8 }, // RLC L
-            0x06 => { let res = self.rlc(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // RLC (HL)
+            0x06 => { let val = self.read(mmu, self.reg.hl()); let res = self.rlc(val); self.write(mmu, self.reg.hl(), res); 16 }, // RLC (HL)
             0x07 => { self.reg.a = self.rlc(self.reg.a); 8 }, // RLC A
             0x08 => { self.reg.b = self.rrc(self.reg.b); 8 }, // RRC B
             0x09 => { self.reg.c = self.rrc(self.reg.c); 8 }, // RRC C
@@ -307,7 +372,7 @@ impl Cpu {
             0x0b => { self.reg.e = self.rrc(self.reg.e); 8 }, // RRC E
             0x0c => { self.reg.h = self.rrc(self.reg.h); 8 }, // RRC H
             0x0d => { self.reg.l = self.rrc(self.reg.l); 8 }, // RRC L
-            0x0e => { let res = self.rrc(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // RRC (HL)
+            0x0e => { let val = self.read(mmu, self.reg.hl()); let res = self.rrc(val); self.write(mmu, self.reg.hl(), res); 16 }, // RRC (HL)
             0x0f => { self.reg.a = self.rrc(self.reg.a); 8 }, // RRC A
             0x10 => { self.reg.b = self.rl(self.reg.b); 8 }, // RL B
             0x11 => { self.reg.c = self.rl(self.reg.c); 8 }, // RL C
@@ -315,7 +380,7 @@ impl Cpu {
             0x13 => { self.reg.e = self.rl(self.reg.e); 8 }, // RL E
             0x14 => { self.reg.h = self.rl(self.reg.h); 8 }, // RL H
             0x15 => { self.reg.l = self.rl(self.reg.l); 8 }, // RL L
-            0x16 => { let res = self.rl(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // RL (HL)
+            0x16 => { let val = self.read(mmu, self.reg.hl()); let res = self.rl(val); self.write(mmu, self.reg.hl(), res); 16 }, // RL (HL)
             0x17 => { self.reg.a = self.rl(self.reg.a); 8 }, // RL A
             0x18 => { self.reg.b = self.rr(self.reg.b); 8 }, // RR B
             0x19 => { self.reg.c = self.rr(self.reg.c); 8 }, // RR C
@@ -323,7 +388,7 @@ impl Cpu {
             0x1b => { self.reg.e = self.rr(self.reg.e); 8 }, // RR E
             0x1c => { self.reg.h = self.rr(self.reg.h); 8 }, // RR H
             0x1d => { self.reg.l = self.rr(self.reg.l); 8 }, // RR L
-            0x1e => { let res = self.rr(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // RR (HL)
+            0x1e => { let val = self.read(mmu, self.reg.hl()); let res = self.rr(val); self.write(mmu, self.reg.hl(), res); 16 }, // RR (HL)
             0x1f => { self.reg.a = self.rr(self.reg.a); 8 }, // RR A
             0x20 => { self.reg.b = self.sla(self.reg.b); 8 }, // SLA B
             0x21 => { self.reg.c = self.sla(self.reg.c); 8 }, // SLA C
@@ -331,7 +396,7 @@ impl Cpu {
             0x23 => { self.reg.e = self.sla(self.reg.e); 8 }, // SLA E
             0x24 => { self.reg.h = self.sla(self.reg.h); 8 }, // SLA H
             0x25 => { self.reg.l = self.sla(self.reg.l); 8 }, // SLA L
-            0x26 => { let res = self.sla(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // SLA (HL)
+            0x26 => { let val = self.read(mmu, self.reg.hl()); let res = self.sla(val); self.write(mmu, self.reg.hl(), res); 16 }, // SLA (HL)
             0x27 => { self.reg.a = self.sla(self.reg.a); 8 }, // SLA B
             0x28 => { self.reg.b = self.sra(self.reg.b); 8 }, // SRA B
             0x29 => { self.reg.c = self.sra(self.reg.c); 8 }, // SRA C
@@ -339,7 +404,7 @@ impl Cpu {
             0x2b => { self.reg.e = self.sra(self.reg.e); 8 }, // SRA E
             0x2c => { self.reg.h = self.sra(self.reg.h); 8 }, // SRA H
             0x2d => { self.reg.l = self.sra(self.reg.l); 8 }, // SRA L
-            0x2e => { let res = self.sra(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // SRA (HL)
+            0x2e => { let val = self.read(mmu, self.reg.hl()); let res = self.sra(val); self.write(mmu, self.reg.hl(), res); 16 }, // SRA (HL)
             0x2f => { self.reg.a = self.sra(self.reg.a); 8 }, // SRA B
             0x30 => { self.reg.b = self.swap(self.reg.b); 8 }, // SWAP B
             0x31 => { self.reg.c = self.swap(self.reg.c); 8 }, // SWAP C
@@ -347,7 +412,7 @@ impl Cpu {
             0x33 => { self.reg.e = self.swap(self.reg.e); 8 }, // SWAP E
             0x34 => { self.reg.h = self.swap(self.reg.h); 8 }, // SWAP H
             0x35 => { self.reg.l = self.swap(self.reg.l); 8 }, // SWAP L
-            0x36 => { let res = self.swap(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // SWAP (HL)
+            0x36 => { let val = self.read(mmu, self.reg.hl()); let res = self.swap(val); self.write(mmu, self.reg.hl(), res); 16 }, // SWAP (HL)
             0x37 => { self.reg.a = self.swap(self.reg.a); 8 }, // SWAP B
             0x38 => { self.reg.b = self.srl(self.reg.b); 8 }, // SRL B
             0x39 => { self.reg.c = self.srl(self.reg.c); 8 }, // SRL C
@@ -355,54 +420,52 @@ impl Cpu {
             0x3b => { self.reg.e = self.srl(self.reg.e); 8 }, // SRL E
             0x3c => { self.reg.h = self.srl(self.reg.h); 8 }, // SRL H
             0x3d => { self.reg.l = self.srl(self.reg.l); 8 }, // SRL L
-            0x3e => { let res = self.srl(mmu.readb(self.reg.hl())); mmu.writeb(self.reg.hl(), res); 16 }, // SRL (HL)
+            0x3e => { let val = self.read(mmu, self.reg.hl()); let res = self.srl(val); self.write(mmu, self.reg.hl(), res); 16 }, // SRL (HL)
             0x3f => { self.reg.a = self.srl(self.reg.a); 8 }, // SRL A
             0x40..=0x7f => {
-                let value = match opcode & 0x0f {
-                    0x0 | 0x8 => self.reg.b,
-                    0x1 | 0x9 => self.reg.c,
-                    0x2 | 0xa => self.reg.d,
-                    0x3 | 0xb => self.reg.e,
-                    0x4 | 0xc => self.reg.h,
-                    0x5 | 0xd => self.reg.l,
-                    0x6 | 0xe => mmu.readb(self.reg.hl()),
-                    0x7 | 0xf => self.reg.a,
-                    _ => panic!("Should not happen!")
+                // The 3 lower bits pick the register, 7 is A
+                let value = match opcode & 0x07 {
+                    0x0 => self.reg.b,
+                    0x1 => self.reg.c,
+                    0x2 => self.reg.d,
+                    0x3 => self.reg.e,
+                    0x4 => self.reg.h,
+                    0x5 => self.reg.l,
+                    0x6 => self.read(mmu, self.reg.hl()),
+                    _ => self.reg.a,
                 };
 
                 let index = (opcode - 0x40) / 8;
                 self.bit(value, index);
-                match opcode & 0xf {
-                    0x6 | 0xe => 12,
+                match opcode & 0x07 {
+                    0x6 => 12,
                     _ => 8
                 }
             }, // BIT index, r8
             0x80..=0xbf => {
                 let index = (opcode - 0x80) / 8;
-                match opcode & 0x0f {
-                    0x0 | 0x8 => { self.reg.b = self.reg.b.unset_bit(index); 8 },
-                    0x1 | 0x9 => { self.reg.c = self.reg.c.unset_bit(index); 8 },
-                    0x2 | 0xa => { self.reg.d = self.reg.d.unset_bit(index); 8 },
-                    0x3 | 0xb => { self.reg.e = self.reg.e.unset_bit(index); 8 },
-                    0x4 | 0xc => { self.reg.h = self.reg.h.unset_bit(index); 8 },
-                    0x5 | 0xd => { self.reg.l = self.reg.l.unset_bit(index); 8 },
-                    0x6 | 0xe => { let val = mmu.readb(self.reg.hl()); mmu.writeb(self.reg.hl(), val.unset_bit(index)); 16 },
-                    0x7 | 0xf => { self.reg.a = self.reg.a.unset_bit(index); 8 },
-                    _ => panic!("Should not happen!")
+                match opcode & 0x07 {
+                    0x0 => { self.reg.b = self.reg.b.unset_bit(index); 8 },
+                    0x1 => { self.reg.c = self.reg.c.unset_bit(index); 8 },
+                    0x2 => { self.reg.d = self.reg.d.unset_bit(index); 8 },
+                    0x3 => { self.reg.e = self.reg.e.unset_bit(index); 8 },
+                    0x4 => { self.reg.h = self.reg.h.unset_bit(index); 8 },
+                    0x5 => { self.reg.l = self.reg.l.unset_bit(index); 8 },
+                    0x6 => { let val = self.read(mmu, self.reg.hl()); self.write(mmu, self.reg.hl(), val.unset_bit(index)); 16 },
+                    _ => { self.reg.a = self.reg.a.unset_bit(index); 8 },
                 }
             }, // RES index, r8
             0xc0..=0xff => {
                 let index = (opcode - 0xc0) / 8;
-                match opcode & 0x0f {
-                    0x0 | 0x8 => { self.reg.b = self.reg.b.set_bit(index); 8 },
-                    0x1 | 0x9 => { self.reg.c = self.reg.c.set_bit(index); 8 },
-                    0x2 | 0xa => { self.reg.d = self.reg.d.set_bit(index); 8 },
-                    0x3 | 0xb => { self.reg.e = self.reg.e.set_bit(index); 8 },
-                    0x4 | 0xc => { self.reg.h = self.reg.h.set_bit(index); 8 },
-                    0x5 | 0xd => { self.reg.l = self.reg.l.set_bit(index); 8 },
-                    0x6 | 0xe => { let val = mmu.readb(self.reg.hl()); mmu.writeb(self.reg.hl(), val.set_bit(index)); 16 },
-                    0x7 | 0xf => { self.reg.a = self.reg.a.set_bit(index); 8 },
-                    _ => panic!("Should not happen!")
+                match opcode & 0x07 {
+                    0x0 => { self.reg.b = self.reg.b.set_bit(index); 8 },
+                    0x1 => { self.reg.c = self.reg.c.set_bit(index); 8 },
+                    0x2 => { self.reg.d = self.reg.d.set_bit(index); 8 },
+                    0x3 => { self.reg.e = self.reg.e.set_bit(index); 8 },
+                    0x4 => { self.reg.h = self.reg.h.set_bit(index); 8 },
+                    0x5 => { self.reg.l = self.reg.l.set_bit(index); 8 },
+                    0x6 => { let val = self.read(mmu, self.reg.hl()); self.write(mmu, self.reg.hl(), val.set_bit(index)); 16 },
+                    _ => { self.reg.a = self.reg.a.set_bit(index); 8 },
                 }
             } // SET index, r8
         }
@@ -658,23 +721,33 @@ impl Cpu {
     fn inc_at(&mut self, mmu: &mut Mmu, address: u16) {
         // INC (address)
         // Sets Z, N, and H
-        let value = mmu.readb(address);
+        let value = self.read(mmu, address);
         let res = value.wrapping_add(1);
         self.reg.set_z(res == 0);
         self.reg.set_n(false);
         self.reg.set_h((value & 0x0f) + 1 > 0x0f);
-        mmu.writeb(address, res);
+        self.write(mmu, address, res);
     }
 
     fn dec_at(&mut self, mmu: &mut Mmu, address: u16) {
         // DEC (address)
         // Sets Z, N, and H
-        let value = mmu.readb(address);
+        let value = self.read(mmu, address);
         let res = value.wrapping_sub(1);
         self.reg.set_z(res == 0);
         self.reg.set_n(true);
         self.reg.set_h((value & 0x0f) == 0);
-        mmu.writeb(address, res);
+        self.write(mmu, address, res);
+    }
+
+    fn halt(&mut self, mmu: &Mmu) {
+        // With an interupt already pending the CPU does not halt. Without
+        // IME to dispatch it, the byte after HALT is read twice.
+        if mmu.pending_interupts() != 0 {
+            self.halt_bug = !self.ime;
+            return;
+        }
+        self.halted = true;
     }
 
     fn inc_hl(&mut self) {
@@ -690,18 +763,19 @@ impl Cpu {
     fn push(&mut self, mmu: &mut Mmu, value: u16) {
         let (msb, lsb) = to_u8(value);
 
-        // TODO: can we simplify with writew ?
+        // Internal cycle decrementing SP before the writes
+        self.tick(mmu);
         self.reg.sp = self.reg.sp.wrapping_sub(1);
-        mmu.writeb(self.reg.sp, msb as u8);
+        self.write(mmu, self.reg.sp, msb);
         self.reg.sp = self.reg.sp.wrapping_sub(1);
-        mmu.writeb(self.reg.sp, lsb as u8);
+        self.write(mmu, self.reg.sp, lsb);
     }
 
-    fn pop(&mut self, mmu: &Mmu) -> u16 {
+    fn pop(&mut self, mmu: &mut Mmu) -> u16 {
         // Returns poped 16bits value as (msb, lsb)
-        let lsb = mmu.readb(self.reg.sp);
+        let lsb = self.read(mmu, self.reg.sp);
         self.reg.sp = self.reg.sp.wrapping_add(1);
-        let msb = mmu.readb(self.reg.sp);
+        let msb = self.read(mmu, self.reg.sp);
         self.reg.sp = self.reg.sp.wrapping_add(1);
 
         to_u16(msb, lsb)
@@ -715,50 +789,129 @@ impl Cpu {
         self.reg.pc = addr;
     }
 
-    fn readb(&mut self, mmu: &Mmu) -> u8 {
-        let byte = mmu.readb(self.reg.pc);
+    fn readb(&mut self, mmu: &mut Mmu) -> u8 {
+        let byte = self.read(mmu, self.reg.pc);
         self.reg.pc = self.reg.pc.wrapping_add(1);
         byte
     }
 
-    fn readw(&mut self, mmu: &Mmu) -> u16 {
-        let word = mmu.readw(self.reg.pc);
-        self.reg.pc = self.reg.pc.wrapping_add(2);
-        word
+    fn readw(&mut self, mmu: &mut Mmu) -> u16 {
+        let lsb = self.readb(mmu);
+        let msb = self.readb(mmu);
+        to_u16(msb, lsb)
     }
 
-    pub fn check_interupts(&mut self, mmu: &mut Mmu) {
+    // One M-cycle: the other components run for 4 cycles
+    fn tick(&mut self, mmu: &mut Mmu) {
+        mmu.update(4);
+        self.cycles += 4;
+    }
+
+    fn read(&mut self, mmu: &mut Mmu, addr: u16) -> u8 {
+        self.tick(mmu);
+        mmu.readb(addr)
+    }
+
+    fn write(&mut self, mmu: &mut Mmu, addr: u16, value: u8) {
+        self.tick(mmu);
+        mmu.writeb(addr, value);
+    }
+
+    // Returns the number of cycles spent dispatching
+    pub fn check_interupts(&mut self, mmu: &mut Mmu) -> u32 {
+        self.cycles = 0;
+
+        // A locked up CPU does not even wake up for interupts
+        if self.lockup.is_some() {
+            return 0
+        }
+
         if !self.ime && !self.halted {
-            return
+            return 0
         }
 
-        let requested = mmu.int_request as u16;
-        let enabled = mmu.int_enabled as u16;
-        let triggered = requested & enabled;
-        if triggered == 0 {
-            return
+        if mmu.pending_interupts() == 0 {
+            return 0
         }
 
-        self.ime = false;
+        // A pending interupt wakes the CPU up, but is only
+        // dispatched with IME set
         self.halted = false;
-
-        for interupt in 0..=4 {
-            if requested.is_set(interupt) && enabled.is_set(interupt) {
-                self.execute_interupt(interupt, mmu);
-            }
+        if !self.ime {
+            return 0
         }
+        self.ime = false;
+
+        self.dispatch_interupt(mmu);
+        self.cycles
     }
 
-    fn execute_interupt(&mut self, interupt: u8, mmu: &mut Mmu) {
-        // Unset request
-        mmu.int_request ^= 1 << interupt;
+    // Two wait states, pushing PC and jumping take 5 M-cycles
+    fn dispatch_interupt(&mut self, mmu: &mut Mmu) {
+        self.tick(mmu);
+        self.tick(mmu);
+
+        let (msb, lsb) = to_u8(self.reg.pc);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(mmu, self.reg.sp, msb);
+
+        // The interupt is only picked once the upper byte of PC is pushed.
+        // When that write cleared IE, nothing is left and PC goes to 0.
+        let pending = mmu.pending_interupts();
+        let routine = match (0..=4).find(|&interupt| pending.is_set(interupt)) {
+            Some(interupt) => {
+                mmu.int_request ^= 1 << interupt;
+                match interupt {
+                    V_BLANK_INTERUPT => V_BLANK_ROUTINE,
+                    STAT_INTERUPT => STAT_ROUTINE,
+                    TIMER_INTERUPT => TIMER_ROUTINE,
+                    SERIAL_INTERUPT => SERIAL_ROUTINE,
+                    _ => JOYPAD_ROUTINE,
+                }
+            }
+            None => 0x0000,
+        };
+
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(mmu, self.reg.sp, lsb);
+        self.tick(mmu);
+        self.reg.pc = routine;
+    }
+}
 
-        match interupt {
-            V_BLANK_INTERUPT => self.call(mmu, V_BLANK_ROUTINE),
-            STAT_INTERUPT => self.call(mmu, STAT_ROUTINE),
-            TIMER_INTERUPT => self.call(mmu, TIMER_ROUTINE),
-            JOYPAD_INTERUPT => self.call(mmu, JOYPAD_ROUTINE),
-            _ => panic!("Should not happen")
+impl Stateful for Cpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.reg.save_state(state);
+        state.write_bool(self.ime);
+        state.write_bool(self.halted);
+        state.write_bool(self.lockup.is_some());
+        if let Some(lockup) = self.lockup {
+            state.write_u16(lockup.pc);
+            state.write_u8(lockup.opcode);
         }
+        state.write_bool(self.ime_scheduled);
+        state.write_bool(self.halt_bug);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.reg.load_state(state)?;
+        self.ime = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        // Lock-ups were not saved before version 4
+        self.lockup = None;
+        if state.version() >= 4 && state.read_bool()? {
+            self.lockup = Some(Lockup {
+                pc: state.read_u16()?,
+                opcode: state.read_u8()?,
+            });
+        }
+        // Neither was emulated before version 7
+        self.ime_scheduled = false;
+        self.halt_bug = false;
+        if state.version() >= 7 {
+            self.ime_scheduled = state.read_bool()?;
+            self.halt_bug = state.read_bool()?;
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
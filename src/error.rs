@@ -0,0 +1,73 @@
+use std::fmt;
+
+use crate::gbs::GbsError;
+use crate::header::HeaderError;
+use crate::header::MbcKind;
+
+// Errors a frontend can recover from, by showing them or
+// stopping a single instance instead of the whole process
+#[derive(Debug)]
+pub enum EmuError {
+    Io(std::io::Error),
+    InvalidHeader(HeaderError),
+    UnknownCartridgeType(u8),
+    UnsupportedCartridge(MbcKind),
+    // Boot ROMs are 256 bytes, holds the size found
+    InvalidBootRom(usize),
+    InvalidGbs(GbsError),
+    // Illegal opcode, only returned when breaking on lock-ups
+    CpuLockup { pc: u16, opcode: u8 },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Io(e) => write!(f, "cannot read ROM: {}", e),
+            EmuError::InvalidHeader(e) => write!(f, "invalid cartridge header: {}", e),
+            EmuError::UnknownCartridgeType(code) => {
+                write!(f, "unknown cartridge type {:#04x}", code)
+            }
+            EmuError::UnsupportedCartridge(mbc) => {
+                write!(f, "{:?} cartridges are not supported", mbc)
+            }
+            EmuError::InvalidBootRom(size) => {
+                write!(f, "boot ROM is {} bytes, expected 256", size)
+            }
+            EmuError::InvalidGbs(e) => write!(f, "invalid GBS file: {}", e),
+            EmuError::CpuLockup { pc, opcode } => write!(
+                f,
+                "CPU locked up on illegal opcode {:#04x} at {:#06x}",
+                opcode, pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmuError::Io(e) => Some(e),
+            EmuError::InvalidHeader(e) => Some(e),
+            EmuError::InvalidGbs(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for EmuError {
+    fn from(e: std::io::Error) -> Self {
+        EmuError::Io(e)
+    }
+}
+
+impl From<HeaderError> for EmuError {
+    fn from(e: HeaderError) -> Self {
+        EmuError::InvalidHeader(e)
+    }
+}
+
+impl From<GbsError> for EmuError {
+    fn from(e: GbsError) -> Self {
+        EmuError::InvalidGbs(e)
+    }
+}
@@ -0,0 +1,243 @@
+use crate::apu::Apu;
+use crate::apu::Channel;
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::cpu::Lockup;
+use crate::error::EmuError;
+use crate::header::CartridgeHeader;
+use crate::joypad::JoypadInput;
+use crate::lcd::Color;
+use crate::lcd::SCREEN_HEIGHT;
+use crate::lcd::SCREEN_WIDTH;
+use crate::mmu::Mmu;
+use crate::mmu::BOOT_ROM_SIZE;
+use crate::model::Model;
+use crate::registers::Registers;
+use crate::rtc::RtcClock;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::serial::SerialDevice;
+
+// Game Boy can execute 4194304 cycles per second
+pub const CLOCK_SPEED: u32 = 4194304;
+// A frame is 154 lines of 456 cycles, so about 59.73 frames per second
+pub const FRAME_CYCLES: u32 = 70224;
+
+// Owns every component of the console so it can be embedded
+// without wiring the CPU, MMU and cartridge by hand
+pub struct GameBoy {
+    cpu: Cpu,
+    mmu: Mmu,
+    // Return EmuError::CpuLockup when the CPU locks up, for debuggers
+    break_on_lockup: bool,
+    // Cycles the last frame ran past FRAME_CYCLES, taken from the next
+    frame_overshoot: u32,
+}
+
+impl GameBoy {
+    pub fn new(cartridge: Cartridge) -> Self {
+        GameBoy::with_model(cartridge, Model::default())
+    }
+
+    // Starts the game as the boot ROM of the model would leave it,
+    // interupts disabled like after power on
+    pub fn with_model(cartridge: Cartridge, model: Model) -> Self {
+        let mut gameboy = GameBoy {
+            cpu: Cpu::power_on(),
+            mmu: Mmu::new(cartridge),
+            break_on_lockup: false,
+            frame_overshoot: 0,
+        };
+        model.apply_post_boot_state(gameboy.cpu.registers_mut(), &mut gameboy.mmu);
+        gameboy
+    }
+
+    // Starts from power on, with the boot ROM mapped over the
+    // cartridge until it writes to 0xFF50. The model is the one
+    // the boot ROM was dumped from.
+    pub fn with_boot_rom(cartridge: Cartridge, boot_rom: Vec<u8>) -> Result<Self, EmuError> {
+        if boot_rom.len() != BOOT_ROM_SIZE {
+            return Err(EmuError::InvalidBootRom(boot_rom.len()));
+        }
+
+        let mut gameboy = GameBoy {
+            cpu: Cpu::power_on(),
+            mmu: Mmu::new(cartridge),
+            break_on_lockup: false,
+            frame_overshoot: 0,
+        };
+        gameboy.mmu.apu = Apu::default();
+        gameboy.mmu.map_boot_rom(boot_rom);
+        Ok(gameboy)
+    }
+
+    pub fn from_rom_bytes(rom: Vec<u8>) -> Result<Self, EmuError> {
+        Ok(GameBoy::new(Cartridge::from_bytes(rom)?))
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.mmu.cartridge.header
+    }
+
+    // Runs a single instruction and lets the other components
+    // catch up. Returns the number of cycles it took.
+    pub fn step_instruction(&mut self) -> Result<u32, EmuError> {
+        let was_locked = self.cpu.lockup().is_some();
+        // The CPU ticks the other components as it goes
+        let mut cycles = self.cpu.run_cycle(&mut self.mmu);
+        cycles += self.cpu.check_interupts(&mut self.mmu);
+
+        match self.cpu.lockup() {
+            Some(Lockup { pc, opcode }) if !was_locked && self.break_on_lockup => {
+                Err(EmuError::CpuLockup { pc, opcode })
+            }
+            _ => Ok(cycles),
+        }
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), EmuError> {
+        let mut cycles = self.frame_overshoot;
+        while cycles < FRAME_CYCLES {
+            cycles += self.step_instruction()?;
+        }
+        self.frame_overshoot = cycles - FRAME_CYCLES;
+        Ok(())
+    }
+
+    // Host sample rate of the audio stream, 0 disables it
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mmu.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mmu.apu.sample_rate()
+    }
+
+    // Audio produced since the last call, as interleaved left and right
+    // samples between -1 and 1. Samples not taken are dropped after a second.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.mmu.apu.take_samples()
+    }
+
+    // Sound state for debuggers, with oscilloscopes of each channel
+    pub fn apu(&self) -> &Apu {
+        &self.mmu.apu
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.mmu.apu.set_muted(channel, muted);
+    }
+
+    pub fn set_channel_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.mmu.apu.set_soloed(channel, soloed);
+    }
+
+    pub fn framebuffer(&self) -> &[[Color; SCREEN_HEIGHT]; SCREEN_WIDTH] {
+        &self.mmu.lcd.screen_data
+    }
+
+    // Cartridge RAM to write to a .sav file, None without battery
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        self.mmu.cartridge.battery_ram()
+    }
+
+    // Content of the .sav file, including the RTC
+    pub fn battery_save(&mut self) -> Option<Vec<u8>> {
+        self.mmu.cartridge.battery_save()
+    }
+
+    pub fn load_battery_save(&mut self, data: &[u8]) {
+        self.mmu.cartridge.load_battery_save(data);
+    }
+
+    // True while the cartridge rumble motor is on
+    pub fn is_rumbling(&self) -> bool {
+        self.mmu.cartridge.is_rumbling()
+    }
+
+    // No effect on cartridges without a clock
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = self.mmu.cartridge.rtc_mut() {
+            rtc.set_clock(clock);
+        }
+    }
+
+    // Snapshot of the whole machine, see savestate.rs for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        self.cpu.save_state(&mut state);
+        self.mmu.save_state(&mut state);
+        state.write_u32(self.frame_overshoot);
+        state.finish(self.mmu.cartridge.rom_hash)
+    }
+
+    // On error the machine is left as it was before the call
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, self.mmu.cartridge.rom_hash)?;
+
+        let backup = self.save_state();
+        let result = self.load_components(&mut state);
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup, self.mmu.cartridge.rom_hash)?;
+            self.load_components(&mut backup)?;
+        }
+        result
+    }
+
+    fn load_components(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load_state(state)?;
+        self.mmu.load_state(state)?;
+        // Frames always started on time before version 9
+        self.frame_overshoot = 0;
+        if state.version() >= 9 {
+            self.frame_overshoot = state.read_u32()?;
+        }
+        state.finish()
+    }
+
+    // Set once the CPU executed an illegal opcode. The machine keeps
+    // running with a hung CPU, as the real one does.
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.cpu.lockup()
+    }
+
+    // Stops the emulation with an error the moment the CPU locks up,
+    // instead of carrying on like the hardware
+    pub fn set_break_on_lockup(&mut self, enabled: bool) {
+        self.break_on_lockup = enabled;
+    }
+
+    pub fn registers(&self) -> &Registers {
+        self.cpu.registers()
+    }
+
+    // Reads memory as the CPU would see it
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.mmu.readb(addr)
+    }
+
+    // Writes memory as the CPU would
+    pub(crate) fn poke(&mut self, addr: u16, value: u8) {
+        self.mmu.writeb(addr, value);
+    }
+
+    // Lets music players set up calls into the ROM, see gbs.rs
+    pub(crate) fn registers_mut(&mut self) -> &mut Registers {
+        self.cpu.registers_mut()
+    }
+
+    // Plugs a device in the link port
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.mmu.serial.set_device(device);
+    }
+
+    pub fn set_button(&mut self, input: JoypadInput, pressed: bool) {
+        if pressed {
+            self.mmu.joypad.on_key_pressed(input);
+        } else {
+            self.mmu.joypad.on_key_released(input);
+        }
+    }
+}
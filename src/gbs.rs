@@ -0,0 +1,440 @@
+use std::fmt;
+
+use crate::apu::Channel;
+use crate::cartridge::read_rom;
+use crate::cartridge::Cartridge;
+use crate::cartridge::Mbc;
+use crate::cartridge::RAM_BANK_SIZE;
+use crate::cartridge::ROM_BANK_SIZE;
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::header::ascii;
+use crate::header::CartridgeHeader;
+use crate::header::HEADER_END;
+use crate::model::Model;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::fnv1a;
+use crate::utils::to_u8;
+use crate::utils::Bits;
+
+// Game Boy Sound file: a 0x70 bytes header followed by the code and
+// data of the music driver, extracted from a game
+const MAGIC: &[u8] = b"GBS";
+const SUPPORTED_VERSION: u8 = 1;
+const GBS_HEADER_SIZE: usize = 0x70;
+
+// The player fills the space below the load address with its own code
+const V_BLANK_VECTOR: usize = 0x40;
+const TIMER_VECTOR: usize = 0x50;
+const DRIVER: u16 = 0x70;
+const TITLE: usize = 0x134;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+
+const LCD_CONTROL_REGISTER: u16 = 0xFF40;
+const TIMER_MODULO_REGISTER: u16 = 0xFF06;
+const TIMER_CONTROL_REGISTER: u16 = 0xFF07;
+const INT_REQUEST_REGISTER: u16 = 0xFF0F;
+const INT_ENABLED_REGISTER: u16 = 0xFFFF;
+
+#[derive(Debug, PartialEq)]
+pub enum GbsError {
+    // File ends before the header does
+    TooShort(usize),
+    NotGbs,
+    UnsupportedVersion(u8),
+    NoSongs,
+    // Code loaded over the player routines
+    InvalidLoadAddress(u16),
+    // Songs are numbered from 0
+    InvalidSong { song: u8, count: u8 },
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::TooShort(size) => {
+                write!(f, "file is {} bytes, too short to hold a GBS header", size)
+            }
+            GbsError::NotGbs => write!(f, "not a GBS file"),
+            GbsError::NoSongs => write!(f, "the file holds no song"),
+            GbsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported GBS version {}", version)
+            }
+            GbsError::InvalidLoadAddress(addr) => {
+                write!(f, "cannot load music code at {:#06x}", addr)
+            }
+            GbsError::InvalidSong { song, count } => {
+                write!(f, "no song {}, the file holds {}", song + 1, count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+#[derive(Clone, Debug)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    // Song to play first, from 0 (the file counts from 1)
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, GbsError> {
+        if data.len() < GBS_HEADER_SIZE {
+            return Err(GbsError::TooShort(data.len()));
+        }
+        if &data[0..3] != MAGIC {
+            return Err(GbsError::NotGbs);
+        }
+        if data[3] != SUPPORTED_VERSION {
+            return Err(GbsError::UnsupportedVersion(data[3]));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let header = GbsHeader {
+            version: data[3],
+            song_count: data[4],
+            // Some rips count from 0, or past the last song
+            first_song: data[5].saturating_sub(1).min(data[4].saturating_sub(1)),
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: ascii(&data[0x10..0x30]),
+            author: ascii(&data[0x30..0x50]),
+            copyright: ascii(&data[0x50..0x70]),
+        };
+
+        if header.song_count == 0 {
+            return Err(GbsError::NoSongs);
+        }
+        if (header.load_address as usize) < HEADER_END || header.load_address >= 0x8000 {
+            return Err(GbsError::InvalidLoadAddress(header.load_address));
+        }
+        Ok(header)
+    }
+
+    // Otherwise the play routine runs on V-Blank
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control.is_set(2)
+    }
+}
+
+// MBC1 like banking of the music data, with RAM always enabled
+struct GbsMbc {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank: usize,
+}
+
+impl GbsMbc {
+    fn new(rom: Vec<u8>) -> Box<Self> {
+        Box::new(GbsMbc {
+            rom,
+            ram: vec![0; RAM_BANK_SIZE as usize],
+            rom_bank: 1,
+        })
+    }
+}
+
+impl Mbc for GbsMbc {
+    fn readb(&self, addr: u16) -> u8 {
+        let index = match addr {
+            0..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => {
+                self.rom_bank * ROM_BANK_SIZE as usize + (addr - ROM_BANK_SIZE) as usize
+            }
+            0xA000..=0xBFFF => return self.ram[(addr - 0xA000) as usize],
+            _ => return 0xFF,
+        };
+        read_rom(&self.rom, index)
+    }
+
+    fn writeb(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x2000..=0x3FFF => self.rom_bank = value.max(1) as usize,
+            0xA000..=0xBFFF => self.ram[(addr - 0xA000) as usize] = value,
+            _ => (),
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+impl Stateful for GbsMbc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.rom_bank as u8);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom_bank = state.read_u8()? as usize;
+        state.read_bytes(&mut self.ram)
+    }
+}
+
+// ROM image the console runs: the music code at its load address,
+// with interupt vectors calling the play routine and a driver
+// calling init before waiting for interupts
+fn build_image(header: &GbsHeader, data: &[u8]) -> Vec<u8> {
+    let load = header.load_address as usize;
+    let code = &data[GBS_HEADER_SIZE..];
+    // Header ROM sizes are powers of two from 32 KiB
+    let size = (load + code.len())
+        .next_power_of_two()
+        .max(ROM_BANK_SIZE as usize * 2);
+    let mut image = vec![0xFF; size];
+
+    // RST instructions jump to the same offset from the load address
+    for rst in (0..V_BLANK_VECTOR).step_by(8) {
+        let (msb, lsb) = to_u8((load + rst) as u16);
+        image[rst..rst + 3].copy_from_slice(&[0xC3, lsb, msb]); // JP
+    }
+    // RETI on every other interupt
+    for vector in (V_BLANK_VECTOR..DRIVER as usize).step_by(8) {
+        image[vector] = 0xD9;
+    }
+    let play_vector = match header.uses_timer() {
+        true => TIMER_VECTOR,
+        false => V_BLANK_VECTOR,
+    };
+    let (msb, lsb) = to_u8(header.play_address);
+    image[play_vector..play_vector + 4].copy_from_slice(&[0xCD, lsb, msb, 0xD9]); // CALL play; RETI
+
+    let driver = DRIVER as usize;
+    let (msb, lsb) = to_u8(header.init_address);
+    image[driver..driver + 8].copy_from_slice(&[
+        0xF3, // DI: play must not run before init returns
+        0xCD, lsb, msb,  // CALL init
+        0xFB, // EI
+        0x76, // HALT
+        0x18, 0xFD, // JR -3
+    ]);
+
+    // Just enough of a cartridge header for CartridgeHeader
+    image[0x100..HEADER_END].fill(0);
+    let title = header.title.as_bytes();
+    let title_len = title.len().min(15);
+    image[TITLE..TITLE + title_len].copy_from_slice(&title[..title_len]);
+    if size > ROM_BANK_SIZE as usize * 2 {
+        image[CARTRIDGE_TYPE] = 0x01; // MBC1
+    }
+    image[ROM_SIZE] = (size / (ROM_BANK_SIZE as usize * 2)).trailing_zeros() as u8;
+
+    image[load..load + code.len()].copy_from_slice(code);
+    image
+}
+
+// Plays the songs of a GBS file on an emulated console
+pub struct GbsPlayer {
+    header: GbsHeader,
+    image: Vec<u8>,
+    gameboy: GameBoy,
+    song: u8,
+    // Kept when switching songs
+    sample_rate: u32,
+}
+
+impl GbsPlayer {
+    // Starts playing the first song
+    pub fn new(data: &[u8]) -> Result<Self, EmuError> {
+        let header = GbsHeader::parse(data)?;
+        let image = build_image(&header, data);
+        let gameboy = GbsPlayer::boot(&header, &image, header.first_song)?;
+        let sample_rate = gameboy.sample_rate();
+
+        Ok(GbsPlayer {
+            song: header.first_song,
+            header,
+            image,
+            gameboy,
+            sample_rate,
+        })
+    }
+
+    fn boot(header: &GbsHeader, image: &[u8], song: u8) -> Result<GameBoy, EmuError> {
+        let cartridge_header = CartridgeHeader::parse(image)?;
+        let cartridge =
+            Cartridge::from_mbc(GbsMbc::new(image.to_vec()), cartridge_header, fnv1a(image));
+        let mut gameboy = GameBoy::with_model(cartridge, Model::Dmg);
+
+        // Music drivers expect cleared RAM
+        for addr in 0xC000..=0xDFFF {
+            gameboy.poke(addr, 0);
+        }
+        for addr in 0xFF80..=0xFFFE {
+            gameboy.poke(addr, 0);
+        }
+        // The boot ROM leaves a V-Blank pending, play must not run before init
+        gameboy.poke(INT_REQUEST_REGISTER, 0);
+
+        if header.uses_timer() {
+            gameboy.poke(TIMER_MODULO_REGISTER, header.timer_modulo);
+            gameboy.poke(TIMER_CONTROL_REGISTER, header.timer_control & 0x07);
+            gameboy.poke(INT_ENABLED_REGISTER, 1 << 2);
+        } else {
+            // V-Blank only happens with the screen on
+            gameboy.poke(LCD_CONTROL_REGISTER, 0x80);
+            gameboy.poke(INT_ENABLED_REGISTER, 1 << 0);
+        }
+
+        let registers = gameboy.registers_mut();
+        registers.a = song;
+        registers.sp = header.stack_pointer;
+        registers.pc = DRIVER;
+        Ok(gameboy)
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    // Song playing, from 0
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    // Restarts the console and calls init for the song
+    pub fn start_song(&mut self, song: u8) -> Result<(), EmuError> {
+        if song >= self.header.song_count {
+            return Err(GbsError::InvalidSong {
+                song,
+                count: self.header.song_count,
+            }
+            .into());
+        }
+
+        let mut gameboy = GbsPlayer::boot(&self.header, &self.image, song)?;
+        gameboy.set_sample_rate(self.sample_rate);
+        for channel in Channel::ALL {
+            gameboy.set_channel_muted(channel, self.gameboy.apu().is_muted(channel));
+            gameboy.set_channel_soloed(channel, self.gameboy.apu().is_soloed(channel));
+        }
+        self.gameboy = gameboy;
+        self.song = song;
+        Ok(())
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), EmuError> {
+        self.gameboy.run_frame()
+    }
+
+    // Host sample rate of the audio stream, 0 disables it
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.gameboy.set_sample_rate(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // See GameBoy::take_samples
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.gameboy.take_samples()
+    }
+
+    // Console running the music driver, to inspect it
+    pub fn gameboy(&self) -> &GameBoy {
+        &self.gameboy
+    }
+
+    // Channels muted or soloed stay so when changing songs
+    pub fn gameboy_mut(&mut self) -> &mut GameBoy {
+        &mut self.gameboy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gbs(load: u16) -> Vec<u8> {
+        let mut data = vec![0; GBS_HEADER_SIZE + 0x8000];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[4] = 3;
+        data[5] = 2;
+        data[6..8].copy_from_slice(&load.to_le_bytes());
+        data[0x10..0x15].copy_from_slice(b"Tunes");
+        data
+    }
+
+    #[test]
+    fn parses_header() {
+        let header = GbsHeader::parse(&gbs(0x400)).unwrap();
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.first_song, 1);
+        assert_eq!(header.load_address, 0x400);
+        assert_eq!(header.title, "Tunes");
+        assert!(!header.uses_timer());
+
+        assert_eq!(
+            GbsHeader::parse(&gbs(0x80)).unwrap_err(),
+            GbsError::InvalidLoadAddress(0x80)
+        );
+        assert_eq!(GbsHeader::parse(b"GBS").unwrap_err(), GbsError::TooShort(3));
+        assert_eq!(GbsHeader::parse(&[0; 0x100]).unwrap_err(), GbsError::NotGbs);
+        let mut data = gbs(0x400);
+        data[4] = 0;
+        assert_eq!(GbsHeader::parse(&data).unwrap_err(), GbsError::NoSongs);
+    }
+
+    #[test]
+    fn music_data_is_banked() {
+        let mut data = gbs(0x400);
+        data[GBS_HEADER_SIZE] = 0x12;
+        data[GBS_HEADER_SIZE + 0x3C00] = 0x56;
+        data[GBS_HEADER_SIZE + 0x7C00] = 0x34;
+        let header = GbsHeader::parse(&data).unwrap();
+        let image = build_image(&header, &data);
+        let mut mbc = GbsMbc::new(image);
+
+        assert_eq!(mbc.readb(0x400), 0x12);
+        assert_eq!(mbc.readb(0x0000), 0xC3);
+        mbc.writeb(0x2000, 2);
+        assert_eq!(mbc.readb(0x4000), 0x34);
+        mbc.writeb(0x2000, 0);
+        assert_eq!(mbc.readb(0x4000), 0x56);
+        // Past the end of the data
+        mbc.writeb(0x2000, 9);
+        assert_eq!(mbc.readb(0x4000), 0xFF);
+    }
+
+    #[test]
+    fn image_size_is_a_power_of_two() {
+        // 0x10400 bytes, 5 banks, need a 128 KiB ROM
+        let mut data = gbs(0x400);
+        data.resize(GBS_HEADER_SIZE + 0x10000, 0);
+        let header = GbsHeader::parse(&data).unwrap();
+        let image = build_image(&header, &data);
+
+        assert_eq!(image.len(), 0x20000);
+        assert_eq!(
+            CartridgeHeader::parse(&image).unwrap().rom_size(),
+            Some(0x20000)
+        );
+    }
+}
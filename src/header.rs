@@ -0,0 +1,382 @@
+use std::fmt;
+
+// Cartridge header, found at 0x100-0x14F of every ROM
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const MANUFACTURER_START: usize = 0x13F;
+const MANUFACTURER_END: usize = 0x143;
+const CGB_FLAG: usize = 0x143;
+const NEW_LICENSEE_START: usize = 0x144;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const OLD_LICENSEE: usize = 0x14B;
+const VERSION: usize = 0x14C;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+pub const HEADER_END: usize = 0x150;
+
+// Old licensee code telling the new one should be used
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+// Logo at 0x104-0x133, the boot ROM does not start a game without it
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    // ROM ends before the header does
+    TooShort(usize),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooShort(size) => write!(
+                f,
+                "ROM is {} bytes, too short to hold a cartridge header",
+                size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CgbSupport {
+    DmgOnly,
+    // Runs on both, with colors on CGB
+    Enhanced,
+    CgbOnly,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    Mmm01,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+// What the cartridge type byte says is on the board
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CartridgeFeatures {
+    pub mbc: MbcKind,
+    pub has_ram: bool,
+    pub has_battery: bool,
+    pub has_rtc: bool,
+    pub has_rumble: bool,
+}
+
+fn features(cartridge_type: u8) -> Option<CartridgeFeatures> {
+    use MbcKind::*;
+
+    // (MBC, RAM, battery, RTC, rumble)
+    let (mbc, has_ram, has_battery, has_rtc, has_rumble) = match cartridge_type {
+        0x00 => (None, false, false, false, false),
+        0x01 => (Mbc1, false, false, false, false),
+        0x02 => (Mbc1, true, false, false, false),
+        0x03 => (Mbc1, true, true, false, false),
+        0x05 => (Mbc2, true, false, false, false),
+        0x06 => (Mbc2, true, true, false, false),
+        0x08 => (None, true, false, false, false),
+        0x09 => (None, true, true, false, false),
+        0x0B => (Mmm01, false, false, false, false),
+        0x0C => (Mmm01, true, false, false, false),
+        0x0D => (Mmm01, true, true, false, false),
+        0x0F => (Mbc3, false, true, true, false),
+        0x10 => (Mbc3, true, true, true, false),
+        0x11 => (Mbc3, false, false, false, false),
+        0x12 => (Mbc3, true, false, false, false),
+        0x13 => (Mbc3, true, true, false, false),
+        0x19 => (Mbc5, false, false, false, false),
+        0x1A => (Mbc5, true, false, false, false),
+        0x1B => (Mbc5, true, true, false, false),
+        0x1C => (Mbc5, false, false, false, true),
+        0x1D => (Mbc5, true, false, false, true),
+        0x1E => (Mbc5, true, true, false, true),
+        0x20 => (Mbc6, true, true, false, false),
+        0x22 => (Mbc7, true, true, false, true),
+        0xFC => (PocketCamera, true, true, false, false),
+        0xFD => (Tama5, true, true, true, false),
+        0xFE => (HuC3, true, true, true, false),
+        0xFF => (HuC1, true, true, false, false),
+        _ => return Option::None,
+    };
+
+    Some(CartridgeFeatures {
+        mbc,
+        has_ram,
+        has_battery,
+        has_rtc,
+        has_rumble,
+    })
+}
+
+// Header byte sum as computed by the boot ROM
+fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+// Sum of every ROM byte but the global checksum itself
+fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(addr, _)| *addr != GLOBAL_CHECKSUM && *addr != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+}
+
+pub(crate) fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| match byte {
+            0x20..=0x7E => *byte as char,
+            _ => '?',
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    // Only on newer cartridges
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub licensee: String,
+    pub cartridge_type: u8,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    // Values computed from the ROM, to compare with the ones above
+    pub computed_header_checksum: u8,
+    pub computed_global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooShort(rom.len()));
+        }
+
+        let cgb_support = match rom[CGB_FLAG] {
+            0xC0 => CgbSupport::CgbOnly,
+            0x80 => CgbSupport::Enhanced,
+            _ => CgbSupport::DmgOnly,
+        };
+
+        // On CGB cartridges the end of the title holds other fields
+        let (title_end, manufacturer_code) = match cgb_support {
+            CgbSupport::DmgOnly => (TITLE_END, None),
+            _ => {
+                let code = &rom[MANUFACTURER_START..MANUFACTURER_END];
+                if code.iter().all(|byte| byte.is_ascii_uppercase()) {
+                    (MANUFACTURER_START, Some(ascii(code)))
+                } else {
+                    (CGB_FLAG, None)
+                }
+            }
+        };
+
+        let licensee = match rom[OLD_LICENSEE] {
+            USE_NEW_LICENSEE => ascii(&rom[NEW_LICENSEE_START..SGB_FLAG]),
+            code => format!("{:02X}", code),
+        };
+
+        Ok(CartridgeHeader {
+            title: ascii(&rom[TITLE_START..title_end]),
+            manufacturer_code,
+            cgb_support,
+            // SGB functions need the old licensee code to be 0x33
+            sgb_support: rom[SGB_FLAG] == 0x03 && rom[OLD_LICENSEE] == USE_NEW_LICENSEE,
+            licensee,
+            cartridge_type: rom[CARTRIDGE_TYPE],
+            rom_size_code: rom[ROM_SIZE],
+            ram_size_code: rom[RAM_SIZE],
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]),
+            computed_header_checksum: header_checksum(rom),
+            computed_global_checksum: global_checksum(rom),
+        })
+    }
+
+    // The boot ROM locks up when this does not match
+    pub fn is_header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    // Never checked by the console, but tells bad dumps apart
+    pub fn is_global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+
+    // None if the cartridge type is unknown
+    pub fn features(&self) -> Option<CartridgeFeatures> {
+        features(self.cartridge_type)
+    }
+
+    pub fn rom_size(&self) -> Option<usize> {
+        match self.rom_size_code {
+            0..=8 => Some(0x8000 << self.rom_size_code),
+            _ => None,
+        }
+    }
+
+    // External RAM size, MBC2 RAM is built in and not counted
+    pub fn ram_size(&self) -> usize {
+        match self.ram_size_code {
+            1 => 0x800, // Unofficial 2KB
+            2 => 0x2000,
+            3 => 0x8000,
+            4 => 0x20000,
+            5 => 0x10000,
+            _ => 0,
+        }
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn checksum_status(valid: bool) -> &'static str {
+    if valid {
+        "ok"
+    } else {
+        "MISMATCH"
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Title:            {}", self.title)?;
+        if let Some(code) = &self.manufacturer_code {
+            writeln!(f, "Manufacturer:     {}", code)?;
+        }
+        writeln!(f, "Licensee:         {}", self.licensee)?;
+        writeln!(f, "CGB:              {:?}", self.cgb_support)?;
+        writeln!(f, "SGB:              {}", yes_no(self.sgb_support))?;
+
+        write!(f, "Cartridge type:   {:02X}", self.cartridge_type)?;
+        match self.features() {
+            Some(features) => {
+                write!(f, " ({:?}", features.mbc)?;
+                for (present, name) in [
+                    (features.has_ram, "RAM"),
+                    (features.has_battery, "battery"),
+                    (features.has_rtc, "RTC"),
+                    (features.has_rumble, "rumble"),
+                ] {
+                    if present {
+                        write!(f, ", {}", name)?;
+                    }
+                }
+                writeln!(f, ")")?;
+            }
+            None => writeln!(f, " (unknown)")?,
+        }
+
+        match self.rom_size() {
+            Some(size) => writeln!(f, "ROM size:         {} KB", size / 1024)?,
+            None => writeln!(f, "ROM size:         unknown ({:02X})", self.rom_size_code)?,
+        }
+        writeln!(f, "RAM size:         {} KB", self.ram_size() / 1024)?;
+        writeln!(f, "Version:          {}", self.version)?;
+        writeln!(
+            f,
+            "Header checksum:  {:02X} ({})",
+            self.header_checksum,
+            checksum_status(self.is_header_checksum_valid())
+        )?;
+        write!(
+            f,
+            "Global checksum:  {:04X} ({})",
+            self.global_checksum,
+            checksum_status(self.is_global_checksum_valid())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"POKE X");
+        rom[MANUFACTURER_START..MANUFACTURER_END].copy_from_slice(b"AAXE");
+        rom[CGB_FLAG] = 0x80;
+        rom[NEW_LICENSEE_START..SGB_FLAG].copy_from_slice(b"01");
+        rom[SGB_FLAG] = 0x03;
+        rom[CARTRIDGE_TYPE] = 0x1B;
+        rom[ROM_SIZE] = 0x00;
+        rom[RAM_SIZE] = 0x03;
+        rom[OLD_LICENSEE] = USE_NEW_LICENSEE;
+        rom[VERSION] = 1;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let global = global_checksum(&rom).to_be_bytes();
+        rom[GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2].copy_from_slice(&global);
+        rom
+    }
+
+    #[test]
+    fn parses_fields() {
+        let header = CartridgeHeader::parse(&rom()).unwrap();
+        assert_eq!(header.title, "POKE X");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("AAXE"));
+        assert_eq!(header.cgb_support, CgbSupport::Enhanced);
+        assert!(header.sgb_support);
+        assert_eq!(header.licensee, "01");
+        assert_eq!(header.rom_size(), Some(0x8000));
+        assert_eq!(header.ram_size(), 0x8000);
+        assert_eq!(header.version, 1);
+
+        let features = header.features().unwrap();
+        assert_eq!(features.mbc, MbcKind::Mbc5);
+        assert!(features.has_battery && !features.has_rumble);
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom = rom();
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.is_header_checksum_valid());
+        assert!(header.is_global_checksum_valid());
+
+        rom[VERSION] = 2;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.is_header_checksum_valid());
+        assert!(!header.is_global_checksum_valid());
+    }
+
+    #[test]
+    fn rejects_short_rom() {
+        assert_eq!(
+            CartridgeHeader::parse(&[0; 0x100]),
+            Err(HeaderError::TooShort(0x100))
+        );
+    }
+}
@@ -1,4 +1,8 @@
 use crate::cpu::JOYPAD_INTERUPT;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 use crate::utils::Bits;
 
 pub const JOYPAD_REGISTER: u16 = 0xFF00;
@@ -28,6 +32,12 @@ impl JoypadInput {
     }
 }
 
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
 pub struct Joypad {
     input_pressed: [bool; 8],
     direction_selected: bool,
@@ -56,7 +66,7 @@ impl Joypad {
         if (input.is_button() && self.button_selected)
             || (input.is_direction() && self.direction_selected)
         {
-            self.int_request |= JOYPAD_INTERUPT;
+            self.int_request |= 1 << JOYPAD_INTERUPT;
         }
     }
 
@@ -64,6 +74,11 @@ impl Joypad {
         self.input_pressed[input as usize] = false;
     }
 
+    // A pressed key on a selected line pulls it low, waking up from STOP
+    pub fn is_input_low(&self) -> bool {
+        self.get_joypad_register() & 0x0f != 0x0f
+    }
+
     fn get_joypad_register(&self) -> u8 {
         // Translate our struct into the GB joypad register format
 
@@ -92,17 +107,33 @@ impl Joypad {
     pub fn readb(&self, addr: u16) -> u8 {
         match addr {
             JOYPAD_REGISTER => self.get_joypad_register(),
-            _ => panic!("Unexpected read for joypad at {:x}", addr),
+            _ => 0xFF, // Not a joypad register
         }
     }
 
     pub fn writeb(&mut self, addr: u16, value: u8) {
-        match addr {
-            JOYPAD_REGISTER => {
-                self.direction_selected = value.is_unset(DIRECTION_BIT);
-                self.button_selected = value.is_unset(BUTTON_BIT);
-            }
-            _ => panic!("Unexpected write for joypad at {:x}", addr),
+        if addr == JOYPAD_REGISTER {
+            self.direction_selected = value.is_unset(DIRECTION_BIT);
+            self.button_selected = value.is_unset(BUTTON_BIT);
+        }
+    }
+}
+
+impl Stateful for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        for pressed in self.input_pressed {
+            state.write_bool(pressed);
+        }
+        state.write_bool(self.direction_selected);
+        state.write_bool(self.button_selected);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for pressed in self.input_pressed.iter_mut() {
+            *pressed = state.read_bool()?;
         }
+        self.direction_selected = state.read_bool()?;
+        self.button_selected = state.read_bool()?;
+        Ok(())
     }
 }
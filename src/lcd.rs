@@ -1,3 +1,7 @@
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 use crate::utils::Bits;
 
 use crate::cpu::STAT_INTERUPT;
@@ -53,6 +57,25 @@ impl Color {
             Color::Black => (0, 0, 0),
         }
     }
+
+    // 0 for the lightest shade up to 3 for the darkest
+    pub fn shade(&self) -> u8 {
+        match self {
+            Color::White => 0,
+            Color::LightGrey => 1,
+            Color::DarkGrey => 2,
+            Color::Black => 3,
+        }
+    }
+
+    pub fn from_shade(shade: u8) -> Color {
+        match shade & 0b11 {
+            0 => Color::White,
+            1 => Color::LightGrey,
+            2 => Color::DarkGrey,
+            _ => Color::Black,
+        }
+    }
 }
 
 struct LcdStatus {
@@ -132,6 +155,12 @@ impl LcdControl {
     }
 }
 
+impl Default for Lcd {
+    fn default() -> Self {
+        Lcd::new()
+    }
+}
+
 pub struct Lcd {
     lcd_control: LcdControl,
     lcd_status: LcdStatus,
@@ -237,7 +266,7 @@ impl Lcd {
         self.lcd_status.mode = 0;
     }
 
-    fn clear_screen(&mut self) {
+    pub fn clear_screen(&mut self) {
         for x in 0..SCREEN_WIDTH {
             for y in 0..SCREEN_HEIGHT {
                 self.screen_data[x][y] = Color::White;
@@ -260,7 +289,7 @@ impl Lcd {
             OBJ_PALETTE_1 => self.obj1_palette,
             WINDOW_Y_REGISTER => self.window_y,
             WINDOW_X_REGISTER => self.window_x,
-            _ => panic!("Unexpected read for PPU at {:x}", addr),
+            _ => 0xFF, // Not a PPU register
         }
     }
 
@@ -284,13 +313,13 @@ impl Lcd {
             OBJ_PALETTE_1 => self.obj1_palette = value,
             WINDOW_Y_REGISTER => self.window_y = value,
             WINDOW_X_REGISTER => self.window_x = value,
-            _ => panic!("Unexpected write for PPU at {:x}", addr),
+            _ => (),
         }
     }
 
     fn get_color(&self, palette_id: u8, palette: u8) -> Color {
         let color_id = match palette_id & 0b11 {
-            0b00 => (palette & 0b11),
+            0b00 => palette & 0b11,
             0b01 => (palette & 0b1100) >> 2,
             0b10 => (palette & 0b110000) >> 4,
             0b11 => (palette & 0b11000000) >> 6,
@@ -364,7 +393,7 @@ impl Lcd {
 
             let color_id = color_data_2.get_bit(color_bit) << 1 | color_data_1.get_bit(color_bit);
             let color = self.get_color(color_id, self.bg_palette);
-            self.screen_data[x as usize][self.lcd_status.curr_line as usize] = color;
+            self.screen_data[x][self.lcd_status.curr_line as usize] = color;
         }
     }
 
@@ -448,3 +477,57 @@ impl Lcd {
         }
     }
 }
+
+impl Stateful for Lcd {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.lcd_control.to_u8());
+        state.write_u8(self.lcd_status.to_u8());
+        state.write_u8(self.lcd_status.lyc);
+        state.write_u8(self.lcd_status.curr_line);
+        state.write_u32(self.scanlines_cycles);
+        state.write_u8(self.scroll_y);
+        state.write_u8(self.scroll_x);
+        state.write_u8(self.window_x);
+        state.write_u8(self.window_y);
+        state.write_u8(self.bg_palette);
+        state.write_u8(self.obj0_palette);
+        state.write_u8(self.obj1_palette);
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+
+        let screen: Vec<u8> = self
+            .screen_data
+            .iter()
+            .flat_map(|column| column.iter().map(|color| color.shade()))
+            .collect();
+        state.write_bytes(&screen);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.lcd_control = LcdControl::from_u8(state.read_u8()?);
+        let status = state.read_u8()?;
+        self.lcd_status.update_modes(status);
+        self.lcd_status.mode = status & 0b11;
+        self.lcd_status.lyc = state.read_u8()?;
+        self.lcd_status.curr_line = state.read_u8()?;
+        self.scanlines_cycles = state.read_u32()?;
+        self.scroll_y = state.read_u8()?;
+        self.scroll_x = state.read_u8()?;
+        self.window_x = state.read_u8()?;
+        self.window_y = state.read_u8()?;
+        self.bg_palette = state.read_u8()?;
+        self.obj0_palette = state.read_u8()?;
+        self.obj1_palette = state.read_u8()?;
+        state.read_bytes(&mut self.vram)?;
+        state.read_bytes(&mut self.oam)?;
+
+        let mut screen = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        state.read_bytes(&mut screen)?;
+        for (x, column) in self.screen_data.iter_mut().enumerate() {
+            for (y, color) in column.iter_mut().enumerate() {
+                *color = Color::from_shade(screen[x * SCREEN_HEIGHT + y]);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+pub mod apu;
+pub mod battery;
+pub mod cartridge;
+pub mod cpu;
+pub mod error;
+mod gameboy;
+pub mod gbs;
+pub mod header;
+pub mod joypad;
+pub mod lcd;
+mod mbc0;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+pub mod model;
+pub mod mmu;
+pub mod pacing;
+pub mod registers;
+pub mod rtc;
+pub mod savestate;
+pub mod screenshot;
+pub mod serial;
+pub mod timer;
+mod utils;
+pub mod wav;
+
+pub use cpu::Lockup;
+pub use error::EmuError;
+pub use gameboy::GameBoy;
+pub use gameboy::CLOCK_SPEED;
+pub use gameboy::FRAME_CYCLES;
+pub use header::CartridgeHeader;
+pub use joypad::JoypadInput;
+pub use lcd::Color;
+pub use lcd::SCREEN_HEIGHT;
+pub use lcd::SCREEN_WIDTH;
+pub use model::Model;
+pub use rtc::RtcClock;
+pub use savestate::StateError;
+pub use serial::SerialDevice;
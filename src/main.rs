@@ -1,52 +1,395 @@
+use std::collections::VecDeque;
 use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
 extern crate minifb;
 use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
 
-mod cartridge;
-mod cpu;
-mod joypad;
-mod lcd;
-mod mbc0;
-mod mbc1;
-mod mbc3;
-mod mmu;
-mod registers;
-mod timer;
-mod utils;
+use gb_rs::apu::Channel;
+use gb_rs::battery::BatterySave;
+use gb_rs::cartridge::Cartridge;
+use gb_rs::gbs::GbsPlayer;
+use gb_rs::pacing;
+use gb_rs::pacing::FrameClock;
+use gb_rs::pacing::Pacing;
+use gb_rs::pacing::Resampler;
+use gb_rs::wav::WavWriter;
+use gb_rs::CartridgeHeader;
+use gb_rs::EmuError;
+use gb_rs::GameBoy;
+use gb_rs::JoypadInput;
+use gb_rs::Model;
+use gb_rs::RtcClock;
+use gb_rs::SCREEN_HEIGHT;
+use gb_rs::SCREEN_WIDTH;
 
-use cartridge::Cartridge;
-use cpu::Cpu;
-use mmu::Mmu;
+const USAGE: &str = "Usage: gb-rs <rom> [--model NAME] [--boot-rom PATH] [--rtc-emulated] \
+[--pacing NAME] [--wav [PATH]] [--track N]\n       gb-rs info <rom>";
 
-use joypad::*;
+// Write cartridge RAM to disk about every second
+const BATTERY_FLUSH_FRAMES: u32 = 60;
 
-use lcd::SCREEN_HEIGHT;
-use lcd::SCREEN_WIDTH;
+// Seconds of sound queued ahead of the sound card. Dynamic rate control
+// keeps the queue around it, and it never holds more than twice as much.
+const AUDIO_LATENCY: f64 = 0.05;
 
-fn run_one_frame(cpu: &mut Cpu, mmu: &mut Mmu) {
-    // Game Boy can execute 4194304 cycles per second
-    // We want 60 frames per second
-    // So we run 69905 each frame
-    const FRAME_CYLES: u32 = 69905;
+// Plays samples of the emulator through the default output device
+struct AudioOutput {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+    resampler: Resampler,
+    // Playback stops when dropped
+    _stream: cpal::Stream,
+}
+
+impl AudioOutput {
+    fn open() -> Result<Self, String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no output device")?;
+        let supported = device.default_output_config().map_err(|e| e.to_string())?;
+        let config = supported.config();
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, queue.clone()),
+            SampleFormat::U8 => build_stream::<u8>(&device, &config, queue.clone()),
+            format => return Err(format!("unsupported sample format {}", format)),
+        }
+        .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(AudioOutput {
+            queue,
+            sample_rate: config.sample_rate.0,
+            resampler: Resampler::default(),
+            _stream: stream,
+        })
+    }
 
-    let mut cycles: u32 = 0;
-    while cycles < FRAME_CYLES {
-        let cpu_cycles = cpu.run_cycle(mmu);
+    // Samples queued when the queue is half full
+    fn target_len(&self) -> usize {
+        (self.sample_rate as f64 * AUDIO_LATENCY) as usize * 2
+    }
+
+    // Stretches the samples a little to keep the queue half full, as the
+    // emulator and the sound card clocks never run at exactly the same rate
+    fn push(&mut self, samples: &[f32]) {
+        let target = self.target_len();
+        let fill = self.queue.lock().unwrap().len() as f64 / (2 * target) as f64;
+        let samples = self.resampler.process(samples, pacing::rate_ratio(fill));
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+        if queue.len() > 2 * target {
+            let excess = queue.len() - 2 * target;
+            queue.drain(..excess);
+        }
+    }
 
-        mmu.update(cpu_cycles);
-        cpu.check_interupts(mmu);
-        cycles += cpu_cycles;
+    // Blocks until the sound card played enough of the queue
+    fn wait(&self) {
+        while self.queue.lock().unwrap().len() > self.target_len() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut cartridge = Cartridge::new(&args[1]);
-    let mut mmu = Mmu::new(&mut cartridge);
-    let mut cpu = Cpu::default();
+// Plays the queued samples, converted to the format of the sound card
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut queue = queue.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                // Silence when the emulator is late
+                let left = queue.pop_front().unwrap_or(0.0);
+                let right = queue.pop_front().unwrap_or(0.0);
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let value = match channel {
+                        0 => left,
+                        1 => right,
+                        _ => (left + right) / 2.0,
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |e| eprintln!("Audio error: {}", e),
+        None,
+    )
+}
 
-    let mut buffer: Vec<u32> = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+// Runs frames at the speed of the console
+struct Pacer {
+    pacing: Pacing,
+    clock: FrameClock,
+}
+
+impl Pacer {
+    fn new(pacing: Pacing) -> Self {
+        Pacer {
+            pacing,
+            clock: FrameClock::default(),
+        }
+    }
+
+    // Falls back on the clock without sound card
+    fn wait(&mut self, audio: Option<&AudioOutput>) {
+        match (self.pacing, audio) {
+            (Pacing::Audio, Some(audio)) => audio.wait(),
+            _ => self.clock.wait(),
+        }
+    }
+
+    fn toggle(&mut self) {
+        self.pacing = match self.pacing {
+            Pacing::Audio => Pacing::Clock,
+            Pacing::Clock => Pacing::Audio,
+        };
+        self.clock.reset();
+        eprintln!("Frames paced by {}", self.pacing);
+    }
+}
+
+const SCOPE_WIDTH: usize = 256;
+const LANE_HEIGHT: usize = 64;
+const LANE_COLORS: [u32; 4] = [0xFFE05050, 0xFFE0B040, 0xFF50C0E0, 0xFF80E060];
+const MUTED_COLOR: u32 = 0xFF505050;
+const WAVE_RAM_COLOR: u32 = 0xFF203040;
+
+// Window showing the output of each sound channel. 1 to 4 mute the
+// channels, with Shift they solo them. D prints the decoded registers.
+struct SoundDebugger {
+    window: Window,
+    buffer: Vec<u32>,
+}
+
+impl SoundDebugger {
+    fn open() -> Self {
+        let window = Window::new(
+            "gb-rs sound",
+            SCOPE_WIDTH,
+            LANE_HEIGHT * 4,
+            WindowOptions {
+                scale: Scale::X2,
+                ..WindowOptions::default()
+            },
+        )
+        .unwrap();
+
+        SoundDebugger {
+            window,
+            buffer: vec![0; SCOPE_WIDTH * LANE_HEIGHT * 4],
+        }
+    }
+
+    fn update(&mut self, gameboy: &mut GameBoy) {
+        self.buffer.fill(0xFF000000);
+        let apu = gameboy.apu();
+        let soloing = Channel::ALL.iter().any(|channel| apu.is_soloed(*channel));
+
+        for (lane, channel) in Channel::ALL.into_iter().enumerate() {
+            let top = lane * LANE_HEIGHT;
+            // Level 0 at the bottom of the lane, 1 at the top
+            let y =
+                |level: f32| top + LANE_HEIGHT - 2 - (level * (LANE_HEIGHT - 4) as f32) as usize;
+
+            if channel == Channel::Wave {
+                // The 32 samples of wave RAM, behind the waveform
+                let samples = apu
+                    .wave_ram()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0x0F]);
+                for (index, sample) in samples.enumerate() {
+                    let width = SCOPE_WIDTH / 32;
+                    for row in y(sample as f32 / 15.0)..top + LANE_HEIGHT - 1 {
+                        let start = row * SCOPE_WIDTH + index * width;
+                        self.buffer[start..start + width - 1].fill(WAVE_RAM_COLOR);
+                    }
+                }
+            }
+
+            let audible = !apu.is_muted(channel) && (!soloing || apu.is_soloed(channel));
+            let color = if audible {
+                LANE_COLORS[lane]
+            } else {
+                MUTED_COLOR
+            };
+            let scope = apu.scope(channel);
+            // Starts on a rising edge so that the waveform stands still
+            let end = scope.len().saturating_sub(SCOPE_WIDTH);
+            let start = (1..end)
+                .find(|index| scope[index - 1] < scope[*index])
+                .unwrap_or(0);
+
+            let mut previous = None;
+            for (x, level) in scope.iter().skip(start).take(SCOPE_WIDTH).enumerate() {
+                let row = y(*level);
+                let (from, to) = match previous {
+                    Some(previous) if previous < row => (previous, row),
+                    Some(previous) => (row, previous),
+                    None => (row, row),
+                };
+                for row in from..=to {
+                    self.buffer[row * SCOPE_WIDTH + x] = color;
+                }
+                previous = Some(row);
+            }
+        }
+
+        self.window
+            .update_with_buffer(&self.buffer, SCOPE_WIDTH, LANE_HEIGHT * 4)
+            .unwrap();
+
+        let shift =
+            self.window.is_key_down(Key::LeftShift) || self.window.is_key_down(Key::RightShift);
+        for key in self.window.get_keys_pressed(KeyRepeat::No) {
+            let channel = match key {
+                Key::Key1 => Channel::Square1,
+                Key::Key2 => Channel::Square2,
+                Key::Key3 => Channel::Wave,
+                Key::Key4 => Channel::Noise,
+                Key::D => {
+                    print_channels(gameboy);
+                    continue;
+                }
+                _ => continue,
+            };
+            if shift {
+                let soloed = gameboy.apu().is_soloed(channel);
+                gameboy.set_channel_soloed(channel, !soloed);
+            } else {
+                let muted = gameboy.apu().is_muted(channel);
+                gameboy.set_channel_muted(channel, !muted);
+            }
+        }
+    }
+}
+
+fn print_channels(gameboy: &GameBoy) {
+    let apu = gameboy.apu();
+    for channel in Channel::ALL {
+        let muted = if apu.is_muted(channel) {
+            " (muted)"
+        } else {
+            ""
+        };
+        let soloed = if apu.is_soloed(channel) {
+            " (solo)"
+        } else {
+            ""
+        };
+        println!(
+            "{:<9}{}{}{}",
+            channel.to_string(),
+            apu.channel_info(channel),
+            muted,
+            soloed
+        );
+    }
+    let wave_ram: Vec<String> = apu
+        .wave_ram()
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    println!("Wave RAM {}", wave_ram.join(""));
+}
+
+fn key_to_input(key: &Key) -> Option<JoypadInput> {
+    match key {
+        Key::Left => Some(JoypadInput::Left),
+        Key::Right => Some(JoypadInput::Right),
+        Key::Up => Some(JoypadInput::Up),
+        Key::Down => Some(JoypadInput::Down),
+        Key::A => Some(JoypadInput::A),
+        Key::S => Some(JoypadInput::B),
+        Key::Enter => Some(JoypadInput::Start),
+        Key::Space => Some(JoypadInput::Select),
+        _ => None,
+    }
+}
+
+fn report_battery_error(battery: &BatterySave, result: std::io::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("Cannot write {}: {}", battery.path().display(), e);
+    }
+}
+
+fn start_recording(path: &Path, sample_rate: u32) -> Option<WavWriter<BufWriter<File>>> {
+    match WavWriter::create(path, sample_rate) {
+        Ok(writer) => {
+            eprintln!("Recording audio to {}", path.display());
+            Some(writer)
+        }
+        Err(e) => {
+            eprintln!("Cannot create {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn stop_recording(path: &Path, writer: WavWriter<BufWriter<File>>) {
+    match writer.finish() {
+        Ok(_) => eprintln!("Audio saved to {}", path.display()),
+        Err(e) => eprintln!("Cannot write {}: {}", path.display(), e),
+    }
+}
+
+// `gb-rs info <rom>`: prints the cartridge header
+fn print_info(path: &str) {
+    let rom = read_rom(path);
+    match CartridgeHeader::parse(&rom) {
+        Ok(header) => println!("{}", header),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_rom(path: &str) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_gameboy(
+    rom: Vec<u8>,
+    boot_rom: Option<&String>,
+    model: Model,
+) -> Result<GameBoy, EmuError> {
+    let cartridge = Cartridge::from_bytes(rom)?;
+    match boot_rom {
+        Some(path) => GameBoy::with_boot_rom(cartridge, std::fs::read(path)?),
+        None => Ok(GameBoy::with_model(cartridge, model)),
+    }
+}
+
+fn open_window() -> Window {
     let mut window = Window::new(
         "gb-rs",
         SCREEN_WIDTH,
@@ -60,14 +403,296 @@ fn main() {
     )
     .unwrap();
 
-    // Make sure that at least 4 ms has passed since the last event poll
-    window.limit_update_rate(Some(std::time::Duration::from_millis(16)));
+    // Frames are paced by Pacer
+    window.limit_update_rate(None);
+    window
+}
+
+// Without a sound card the game runs muted, but can still be recorded
+fn open_audio() -> Option<AudioOutput> {
+    match AudioOutput::open() {
+        Ok(audio) => Some(audio),
+        Err(e) => {
+            eprintln!("Audio disabled: {}", e);
+            None
+        }
+    }
+}
+
+// Next to the ROM unless given after --wav
+fn wav_path(args: &[String]) -> PathBuf {
+    match arg_value(args, "--wav") {
+        Some(path) if !path.starts_with("--") => PathBuf::from(path),
+        _ => Path::new(&args[1]).with_extension("wav"),
+    }
+}
+
+// Sound output, recording, pacing and sound debugger, with the F6, F9
+// and F10 keys driving them. Shared by games and GBS files.
+struct Frontend {
+    audio: Option<AudioOutput>,
+    pacer: Pacer,
+    sound_debugger: Option<SoundDebugger>,
+    wav_path: PathBuf,
+    wav: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl Frontend {
+    // The emulator must already output at the rate of the audio stream
+    fn new(args: &[String], audio: Option<AudioOutput>, sample_rate: u32) -> Self {
+        let wav_path = wav_path(args);
+        let wav = if args.iter().any(|arg| arg == "--wav") {
+            start_recording(&wav_path, sample_rate)
+        } else {
+            None
+        };
+
+        Frontend {
+            audio,
+            pacer: Pacer::new(parse_pacing(args)),
+            sound_debugger: None,
+            wav_path,
+            wav,
+        }
+    }
+
+    fn wait(&mut self) {
+        self.pacer.wait(self.audio.as_ref());
+    }
+
+    fn play(&mut self, samples: &[f32]) {
+        if let Some(audio) = &mut self.audio {
+            audio.push(samples);
+        }
+        if let Some(writer) = &mut self.wav {
+            if let Err(e) = writer.write_samples(samples) {
+                eprintln!("Cannot write {}: {}", self.wav_path.display(), e);
+                self.wav = None;
+            }
+        }
+    }
+
+    fn update_debugger(&mut self, gameboy: &mut GameBoy) {
+        if let Some(debugger) = &mut self.sound_debugger {
+            debugger.update(gameboy);
+            if !debugger.window.is_open() {
+                self.sound_debugger = None;
+            }
+        }
+    }
+
+    fn handle_keys(&mut self, pressed: &[Key], sample_rate: u32) {
+        if pressed.contains(&Key::F10) {
+            self.sound_debugger = match self.sound_debugger {
+                Some(_) => None,
+                None => Some(SoundDebugger::open()),
+            };
+        }
+        if pressed.contains(&Key::F6) {
+            self.pacer.toggle();
+        }
+        if pressed.contains(&Key::F9) {
+            match self.wav.take() {
+                Some(writer) => stop_recording(&self.wav_path, writer),
+                None => self.wav = start_recording(&self.wav_path, sample_rate),
+            }
+        }
+    }
+
+    fn finish(self) {
+        if let Some(writer) = self.wav {
+            stop_recording(&self.wav_path, writer);
+        }
+    }
+}
+
+// GBS files play in a blank window, Left and Right change the song
+fn play_gbs(args: &[String], data: &[u8]) {
+    let mut player = match GbsPlayer::new(data) {
+        Ok(player) => player,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(track) = arg_value(args, "--track") {
+        // Tracks are numbered from 1
+        let song = match track.parse::<u8>() {
+            Ok(track) if track > 0 => track - 1,
+            _ => {
+                eprintln!("Invalid track {}", track);
+                std::process::exit(2);
+            }
+        };
+        if let Err(e) = player.start_song(song) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let audio = open_audio();
+    if let Some(audio) = &audio {
+        player.set_sample_rate(audio.sample_rate);
+    }
+    let mut frontend = Frontend::new(args, audio, player.sample_rate());
+
+    let buffer: Vec<u32> = vec![0xFFFFFFFF; SCREEN_WIDTH * SCREEN_HEIGHT];
+    let mut window = open_window();
+    let song_count = player.header().song_count;
 
     while window.is_open() {
-        run_one_frame(&mut cpu, &mut mmu);
+        frontend.wait();
+        window.set_title(&format!(
+            "gb-rs - {} ({}/{})",
+            player.header().title,
+            player.song() + 1,
+            song_count
+        ));
+
+        if let Err(e) = player.run_frame() {
+            eprintln!("{}", e);
+            break;
+        }
+        frontend.play(&player.take_samples());
+
+        window
+            .update_with_buffer(&buffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+            .unwrap();
+        frontend.update_debugger(player.gameboy_mut());
+
+        let pressed = window.get_keys_pressed(KeyRepeat::No);
+        let song = player.song();
+        let next = if pressed.contains(&Key::Right) {
+            Some((song + 1) % song_count)
+        } else if pressed.contains(&Key::Left) {
+            Some(song.checked_sub(1).unwrap_or(song_count - 1))
+        } else {
+            None
+        };
+        if let Some(next) = next {
+            if let Err(e) = player.start_song(next) {
+                eprintln!("{}", e);
+            }
+        }
+        frontend.handle_keys(&pressed, player.sample_rate());
+    }
+
+    frontend.finish();
+}
+
+fn parse_pacing(args: &[String]) -> Pacing {
+    match arg_value(args, "--pacing").map(|name| name.parse()) {
+        Some(Ok(pacing)) => pacing,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        None => Pacing::default(),
+    }
+}
+
+// Value following a `--flag` argument
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && args[1] == "info" {
+        print_info(&args[2]);
+        return;
+    }
+
+    if args.len() < 2 || args[1].starts_with("--") {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let rom = read_rom(&args[1]);
+    if rom.starts_with(b"GBS") {
+        play_gbs(&args, &rom);
+        return;
+    }
+    if args.iter().any(|arg| arg == "--track") {
+        eprintln!("--track only applies to GBS files");
+        std::process::exit(2);
+    }
+
+    // The boot ROM decides which model runs
+    if arg_value(&args, "--boot-rom").is_some() && arg_value(&args, "--model").is_some() {
+        eprintln!("--boot-rom and --model cannot be used together");
+        std::process::exit(2);
+    }
+    let model = match arg_value(&args, "--model").map(|name| name.parse()) {
+        Some(Ok(model)) => model,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        None => Model::default(),
+    };
+    let mut gameboy = match load_gameboy(rom, arg_value(&args, "--boot-rom"), model) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+    if !gameboy.header().is_header_checksum_valid() {
+        eprintln!("Warning: header checksum does not match, a real console would not boot");
+    }
+    let state_path = Path::new(&args[1]).with_extension("state");
+
+    // Cartridge clocks follow the host clock unless asked otherwise
+    if args.iter().any(|arg| arg == "--rtc-emulated") {
+        gameboy.set_rtc_clock(RtcClock::Emulated);
+    } else {
+        gameboy.set_rtc_clock(RtcClock::Host);
+    }
+
+    let mut battery = BatterySave::for_rom(Path::new(&args[1]));
+    if let Err(e) = battery.load(&mut gameboy) {
+        eprintln!("Cannot load {}: {}", battery.path().display(), e);
+    }
+
+    let audio = open_audio();
+    if let Some(audio) = &audio {
+        gameboy.set_sample_rate(audio.sample_rate);
+    }
+    let mut frontend = Frontend::new(&args, audio, gameboy.sample_rate());
+
+    let mut buffer: Vec<u32> = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+    let mut window = open_window();
+
+    let mut frames_since_flush = 0;
+    let mut lockup_reported = false;
+    while window.is_open() {
+        frontend.wait();
+        if let Err(e) = gameboy.run_frame() {
+            eprintln!("{}", e);
+            break;
+        }
+        if let (Some(lockup), false) = (gameboy.lockup(), lockup_reported) {
+            eprintln!(
+                "CPU locked up on illegal opcode {:#04x} at {:#06x}",
+                lockup.opcode, lockup.pc
+            );
+            lockup_reported = true;
+        }
+        frontend.play(&gameboy.take_samples());
+        frames_since_flush += 1;
+        if frames_since_flush >= BATTERY_FLUSH_FRAMES {
+            let result = battery.flush(&mut gameboy);
+            report_battery_error(&battery, result);
+            frames_since_flush = 0;
+        }
+
+        let screen = gameboy.framebuffer();
         for x in 0..SCREEN_WIDTH {
             for y in 0..SCREEN_HEIGHT {
-                let (r, g, b) = mmu.lcd.screen_data[x][y].rgb();
+                let (r, g, b) = screen[x][y].rgb();
                 buffer[y * SCREEN_WIDTH + x] =
                     0xFF000000 | (r as u32) << 16 | (g as u32) << 8 | (b as u32);
             }
@@ -75,32 +700,40 @@ fn main() {
         window
             .update_with_buffer(&buffer, SCREEN_WIDTH, SCREEN_HEIGHT)
             .unwrap();
+        frontend.update_debugger(&mut gameboy);
+
+        let pressed = window.get_keys_pressed(KeyRepeat::No);
+        pressed
+            .iter()
+            .filter_map(key_to_input)
+            .for_each(|input| gameboy.set_button(input, true));
+
+        if pressed.contains(&Key::F5) {
+            if let Err(e) = std::fs::write(&state_path, gameboy.save_state()) {
+                eprintln!("Cannot save state: {}", e);
+            }
+        }
+        frontend.handle_keys(&pressed, gameboy.sample_rate());
+        if pressed.contains(&Key::F8) {
+            match std::fs::read(&state_path) {
+                Ok(state) => {
+                    if let Err(e) = gameboy.load_state(&state) {
+                        eprintln!("Cannot load state: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Cannot read state: {}", e),
+            }
+        }
 
         window
-            .get_keys_pressed(KeyRepeat::No)
+            .get_keys_released()
             .iter()
-            .for_each(|key| match key {
-                Key::Left => mmu.joypad.on_key_pressed(JoypadInput::Left),
-                Key::Right => mmu.joypad.on_key_pressed(JoypadInput::Right),
-                Key::Up => mmu.joypad.on_key_pressed(JoypadInput::Up),
-                Key::Down => mmu.joypad.on_key_pressed(JoypadInput::Down),
-                Key::A => mmu.joypad.on_key_pressed(JoypadInput::A),
-                Key::S => mmu.joypad.on_key_pressed(JoypadInput::B),
-                Key::Enter => mmu.joypad.on_key_pressed(JoypadInput::Start),
-                Key::Space => mmu.joypad.on_key_pressed(JoypadInput::Select),
-                _ => (),
-            });
-
-        window.get_keys_released().iter().for_each(|key| match key {
-            Key::Left => mmu.joypad.on_key_released(JoypadInput::Left),
-            Key::Right => mmu.joypad.on_key_released(JoypadInput::Right),
-            Key::Up => mmu.joypad.on_key_released(JoypadInput::Up),
-            Key::Down => mmu.joypad.on_key_released(JoypadInput::Down),
-            Key::A => mmu.joypad.on_key_released(JoypadInput::A),
-            Key::S => mmu.joypad.on_key_released(JoypadInput::B),
-            Key::Enter => mmu.joypad.on_key_released(JoypadInput::Start),
-            Key::Space => mmu.joypad.on_key_released(JoypadInput::Select),
-            _ => (),
-        });
+            .filter_map(key_to_input)
+            .for_each(|input| gameboy.set_button(input, false));
     }
+
+    frontend.finish();
+
+    let result = battery.save(&mut gameboy);
+    report_battery_error(&battery, result);
 }
@@ -1,24 +1,93 @@
+use crate::cartridge::read_rom;
 use crate::cartridge::Mbc;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 
+// ROM only, or ROM and RAM (types 0x08 and 0x09). Without a controller
+// the RAM cannot be disabled nor banked.
 pub struct NoMbc {
     rom: Vec<u8>,
+    ram: Vec<u8>,
 }
 
 impl NoMbc {
-    pub fn new(rom: Vec<u8>) -> Box<Self> {
-        Box::new(NoMbc { rom })
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Box<Self> {
+        Box::new(NoMbc {
+            rom,
+            ram: vec![0; ram_size],
+        })
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        // Smaller RAM chips echo across the area
+        Some((addr - 0xA000) as usize % self.ram.len())
     }
 }
 
 impl Mbc for NoMbc {
     fn readb(&self, addr: u16) -> u8 {
-        *self
-            .rom
-            .get(addr as usize)
-            .expect(&format!("Cannot access cartridge memory at {:#08x}", addr)[..])
+        if (0xA000..=0xBFFF).contains(&addr) {
+            return match self.ram_index(addr) {
+                Some(index) => self.ram[index],
+                None => 0xFF,
+            };
+        }
+        read_rom(&self.rom, addr as usize)
+    }
+
+    fn writeb(&mut self, addr: u16, value: u8) {
+        // Writes to ROM are ignored
+        if (0xA000..0xC000).contains(&addr) {
+            if let Some(index) = self.ram_index(addr) {
+                self.ram[index] = value;
+            }
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
     }
+}
+
+// Nothing but ROM, and the RAM if any. ROM only cartridges
+// save nothing, as before RAM was supported.
+impl Stateful for NoMbc {
+    fn save_state(&self, state: &mut StateWriter) {
+        if !self.ram.is_empty() {
+            state.write_bytes(&self.ram);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if self.ram.is_empty() {
+            return Ok(());
+        }
+        state.read_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_is_always_enabled() {
+        let mut mbc = NoMbc::new(vec![0; 0x8000], 0x800);
+        mbc.writeb(0xA000, 0x42);
+        assert_eq!(mbc.readb(0xA000), 0x42);
+        assert_eq!(mbc.readb(0xA800), 0x42);
 
-    fn writeb(&mut self, _addr: u16, _value: u8) {
-        // No write is supposed to happen.
+        let mut mbc = NoMbc::new(vec![0; 0x8000], 0);
+        mbc.writeb(0xA000, 0x42);
+        assert_eq!(mbc.readb(0xA000), 0xFF);
     }
 }
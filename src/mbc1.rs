@@ -1,26 +1,53 @@
+use crate::cartridge::read_rom;
 use crate::cartridge::Mbc;
 use crate::cartridge::RAM_BANK_SIZE;
 use crate::cartridge::ROM_BANK_SIZE;
+use crate::header::NINTENDO_LOGO;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+
+// Multicarts are 1MB, each game starting with its own logo
+const MULTICART_SIZE: usize = 0x100000;
+const MULTICART_GAME_BANKS: usize = 0x10;
+const LOGO_START: usize = 0x104;
+const LOGO_END: usize = 0x134;
 
 pub struct Mbc1 {
     rom: Vec<u8>,
     is_ram_enabled: bool,
-    // If true 16Mb ROM/8KB RAM else 4Mb ROM/32KB RAM
-    is_rom_banking: bool,
-    current_rom_bank: u8,
-    current_ram_bank: u8,
-    ram_banks: [u8; 0x8000],
+    // Mode 1: BANK2 also applies to 0x0000-0x3FFF and RAM
+    is_advanced_mode: bool,
+    // 5 bits, written at 0x2000-0x3FFF, never 0
+    bank1: u8,
+    // 2 bits, written at 0x4000-0x5FFF: upper ROM bank bits or RAM bank
+    bank2: u8,
+    // MBC1M: BANK1 only has 4 bits wired to the ROM
+    is_multicart: bool,
+    ram_banks: Vec<u8>,
+}
+
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != MULTICART_SIZE {
+        return false;
+    }
+    // The header of a second game is found at bank 0x10, an ordinary
+    // game rarely holds the logo there
+    let second_game = MULTICART_GAME_BANKS * ROM_BANK_SIZE as usize;
+    rom[second_game + LOGO_START..second_game + LOGO_END] == NINTENDO_LOGO
 }
 
 impl Mbc1 {
-    pub fn new(rom: Vec<u8>) -> Box<Self> {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Box<Self> {
         Box::new(Mbc1 {
+            is_multicart: is_multicart(&rom),
             rom,
             is_ram_enabled: false,
-            is_rom_banking: true,
-            current_rom_bank: 1,
-            current_ram_bank: 0,
-            ram_banks: [0; 0x8000], // Max 32KB of RAM
+            is_advanced_mode: false,
+            bank1: 1,
+            bank2: 0,
+            ram_banks: vec![0; ram_size],
         })
     }
 
@@ -29,82 +56,210 @@ impl Mbc1 {
         self.is_ram_enabled = (value & 0xf) == 0xA;
     }
 
-    fn change_low_rom_bank(&mut self, value: u8) {
-        // Replace 5 lowest bits with value's ones
-        let lower5 = value & 0b11111;
-        self.current_rom_bank &= 0b11100000;
-        self.current_rom_bank |= lower5;
-
-        self.current_rom_bank = match self.current_rom_bank {
+    fn change_bank1(&mut self, value: u8) {
+        // Zero is checked on the 5 bits, so 0x20 also selects bank 1
+        self.bank1 = match value & 0b11111 {
             0 => 1,
-            0x20 => 0x21,
-            0x40 => 0x41,
-            0x60 => 0x61,
-            _ => self.current_rom_bank,
+            bank => bank,
         };
     }
 
-    fn change_hi_rom_bank(&mut self, data: u8) {
-        // Change the highest 3 bits of self.current_rom_bank
-        self.current_rom_bank &= 0b11111;
-        let bits = data & 0b11100000;
-        self.current_rom_bank |= bits;
+    // Bits BANK2 is shifted by in the ROM bank number
+    fn bank2_shift(&self) -> u8 {
+        if self.is_multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE as usize).max(1)
+    }
 
-        if self.current_rom_bank == 0 {
-            self.current_rom_bank = 1;
+    // Bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> usize {
+        if !self.is_advanced_mode {
+            return 0;
         }
+        ((self.bank2 as usize) << self.bank2_shift()) % self.rom_bank_count()
     }
 
-    fn change_ram_bank(&mut self, data: u8) {
-        self.current_ram_bank = data & 0x3;
+    // Bank mapped at 0x4000-0x7FFF
+    fn high_rom_bank(&self) -> usize {
+        let bank1 = if self.is_multicart {
+            self.bank1 & 0x0F
+        } else {
+            self.bank1
+        };
+        let bank = (self.bank2 as usize) << self.bank2_shift() | bank1 as usize;
+        // Bank bits above the ROM size are not wired
+        bank % self.rom_bank_count()
     }
 
-    fn change_rom_ram_mode(&mut self, data: u8) {
-        self.is_rom_banking = data & 0x1 == 0;
-        if self.is_rom_banking {
-            // Game Boy can only use bank 0 in this mode
-            self.current_ram_bank = 0;
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.is_ram_enabled || self.ram_banks.is_empty() {
+            return None;
         }
+        let bank = if self.is_advanced_mode { self.bank2 } else { 0 };
+        let index = (addr - 0xA000) as usize + bank as usize * RAM_BANK_SIZE as usize;
+        // 8KB and smaller RAMs ignore the bank number
+        Some(index % self.ram_banks.len())
     }
 }
 
 impl Mbc for Mbc1 {
     fn readb(&self, addr: u16) -> u8 {
         if (0xA000..=0xBFFF).contains(&addr) {
-            return self.ram_banks
-                [((addr - 0xA000) + (self.current_ram_bank as u16) * RAM_BANK_SIZE) as usize];
+            return match self.ram_index(addr) {
+                Some(index) => self.ram_banks[index],
+                None => 0xFF,
+            };
         }
 
         let real_addr = match addr {
+            0x0000..=0x3FFF => addr as usize + self.low_rom_bank() * ROM_BANK_SIZE as usize,
             0x4000..=0x7FFF => {
-                (addr - ROM_BANK_SIZE) + ((self.current_rom_bank as u16) * ROM_BANK_SIZE) as u16
+                (addr - ROM_BANK_SIZE) as usize + self.high_rom_bank() * ROM_BANK_SIZE as usize
             }
-            _ => addr,
-        } as usize;
+            _ => addr as usize,
+        };
 
-        *self
-            .rom
-            .get(real_addr)
-            .expect(&format!("Cannot access cartridge memory at {:#08x}", real_addr)[..])
+        read_rom(&self.rom, real_addr)
     }
 
     fn writeb(&mut self, addr: u16, value: u8) {
         if (0xA000..0xC000).contains(&addr) {
             // Cartridge RAM bank write
-            self.ram_banks[(addr - 0xA000) as usize] = value;
+            if let Some(index) = self.ram_index(addr) {
+                self.ram_banks[index] = value;
+            }
         } else if addr < 0x2000 {
             self.toggle_ram_banking(value);
         } else if addr < 0x4000 {
-            // ROM bank change
-            self.change_low_rom_bank(value);
+            self.change_bank1(value);
         } else if addr < 0x6000 {
-            if self.is_rom_banking {
-                self.change_hi_rom_bank(value);
-            } else {
-                self.change_ram_bank(value);
-            }
+            self.bank2 = value & 0b11;
         } else if addr < 0x8000 {
-            self.change_rom_ram_mode(value);
+            self.is_advanced_mode = value & 0x1 == 1;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram_banks
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram_banks
+    }
+}
+
+impl Stateful for Mbc1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_ram_enabled);
+        state.write_bool(self.is_advanced_mode);
+        state.write_u8(self.bank1);
+        state.write_u8(self.bank2);
+        state.write_bytes(&self.ram_banks);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_ram_enabled = state.read_bool()?;
+        if state.version() < 3 {
+            // Saved as ROM banking flag, full ROM bank and RAM bank
+            let is_rom_banking = state.read_bool()?;
+            let rom_bank = state.read_u8()?;
+            let ram_bank = state.read_u8()?;
+            self.is_advanced_mode = !is_rom_banking;
+            self.change_bank1(rom_bank);
+            self.bank2 = if is_rom_banking {
+                (rom_bank >> 5) & 0b11
+            } else {
+                ram_bank & 0b11
+            };
+        } else {
+            self.is_advanced_mode = state.read_bool()?;
+            self.bank1 = state.read_u8()?;
+            self.bank2 = state.read_u8()?;
+        }
+        state.read_bytes(&mut self.ram_banks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each bank starts with its own number
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE as usize];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE as usize] = bank as u8;
         }
+        rom
+    }
+
+    #[test]
+    fn ram_is_gated_and_banked() {
+        let mut mbc = Mbc1::new(rom(4), 0x8000);
+        mbc.writeb(0xA000, 0x42);
+        assert_eq!(mbc.readb(0xA000), 0xFF);
+
+        mbc.writeb(0x0000, 0x0A);
+        mbc.writeb(0x6000, 0x01);
+        mbc.writeb(0x4000, 0x02);
+        mbc.writeb(0xA000, 0x42);
+        assert_eq!(mbc.readb(0xA000), 0x42);
+
+        mbc.writeb(0x4000, 0x00);
+        assert_eq!(mbc.readb(0xA000), 0x00);
+        assert_eq!(mbc.ram()[2 * RAM_BANK_SIZE as usize], 0x42);
+    }
+
+    #[test]
+    fn bank_numbers_are_masked() {
+        let mut mbc = Mbc1::new(rom(8), 0);
+        mbc.writeb(0x2000, 0x1D);
+        assert_eq!(mbc.readb(0x4000), 5);
+
+        // 0x20 selects bank 1 like 0x00
+        mbc.writeb(0x2000, 0x20);
+        assert_eq!(mbc.readb(0x4000), 1);
+    }
+
+    #[test]
+    fn mode_1_remaps_low_area() {
+        let mut mbc = Mbc1::new(rom(128), 0);
+        mbc.writeb(0x4000, 0x02);
+        mbc.writeb(0x2000, 0x03);
+        assert_eq!(mbc.readb(0x0000), 0);
+        assert_eq!(mbc.readb(0x4000), 0x43);
+
+        mbc.writeb(0x6000, 0x01);
+        assert_eq!(mbc.readb(0x0000), 0x40);
+    }
+
+    #[test]
+    fn multicart_uses_4_bit_banks() {
+        let mut rom = rom(64);
+        for game in 0..4 {
+            let start = game * MULTICART_GAME_BANKS * ROM_BANK_SIZE as usize;
+            rom[start + LOGO_START..start + LOGO_END].copy_from_slice(&NINTENDO_LOGO);
+        }
+        let mut mbc = Mbc1::new(rom, 0);
+        assert!(mbc.is_multicart);
+
+        mbc.writeb(0x4000, 0x01);
+        mbc.writeb(0x2000, 0x12);
+        assert_eq!(mbc.readb(0x4000), 0x12);
+        mbc.writeb(0x6000, 0x01);
+        assert_eq!(mbc.readb(0x0000), 0x10);
+    }
+
+    #[test]
+    fn matching_banks_are_not_a_multicart() {
+        // Same bytes at bank 0 and 0x10, but no logo
+        let mbc = Mbc1::new(rom(64), 0);
+        assert!(!mbc.is_multicart);
     }
 }
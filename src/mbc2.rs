@@ -0,0 +1,132 @@
+use crate::cartridge::read_rom;
+use crate::cartridge::Mbc;
+use crate::cartridge::ROM_BANK_SIZE;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::Bits;
+
+// 512 half bytes built in the MBC itself
+const RAM_SIZE: usize = 512;
+
+// Bit 8 of the address selects the register written in 0x0000-0x3FFF
+const REGISTER_SELECT_BIT: u8 = 8;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    is_ram_enabled: bool,
+    current_rom_bank: u8,
+    // Only the lower 4 bits of each byte are used
+    ram: Vec<u8>,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Box<Self> {
+        Box::new(Mbc2 {
+            rom,
+            is_ram_enabled: false,
+            current_rom_bank: 1,
+            ram: vec![0; RAM_SIZE],
+        })
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE as usize).max(1)
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn readb(&self, addr: u16) -> u8 {
+        if (0xA000..=0xBFFF).contains(&addr) {
+            if !self.is_ram_enabled {
+                return 0xFF;
+            }
+            // Only 9 address bits are decoded, RAM echoes over the whole area
+            // Upper half of each byte is not connected and reads as 1s
+            return self.ram[addr as usize % RAM_SIZE] | 0xF0;
+        }
+
+        let real_addr = match addr {
+            0x4000..=0x7FFF => {
+                let bank = self.current_rom_bank as usize % self.rom_bank_count();
+                (addr - ROM_BANK_SIZE) as usize + bank * ROM_BANK_SIZE as usize
+            }
+            _ => addr as usize,
+        };
+
+        read_rom(&self.rom, real_addr)
+    }
+
+    fn writeb(&mut self, addr: u16, value: u8) {
+        if (0xA000..0xC000).contains(&addr) {
+            if self.is_ram_enabled {
+                self.ram[addr as usize % RAM_SIZE] = value & 0x0F;
+            }
+        } else if addr < 0x4000 {
+            if addr.is_set(REGISTER_SELECT_BIT) {
+                // 16 ROM banks, bank 0 selects bank 1
+                self.current_rom_bank = match value & 0x0F {
+                    0 => 1,
+                    bank => bank,
+                };
+            } else {
+                self.is_ram_enabled = (value & 0xF) == 0xA;
+            }
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+impl Stateful for Mbc2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_ram_enabled);
+        state.write_u8(self.current_rom_bank);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_ram_enabled = state.read_bool()?;
+        self.current_rom_bank = state.read_u8()?;
+        state.read_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_bit_8_selects_register() {
+        let mut rom = vec![0; 16 * ROM_BANK_SIZE as usize];
+        rom[3 * ROM_BANK_SIZE as usize] = 3;
+        let mut mbc = Mbc2::new(rom);
+
+        // Bit 8 clear: RAM enable, the bank is unchanged
+        mbc.writeb(0x0000, 0x0A);
+        assert_eq!(mbc.readb(0x4000), 0);
+        assert_ne!(mbc.readb(0xA000), 0xFF);
+
+        mbc.writeb(0x2100, 0x03);
+        assert_eq!(mbc.readb(0x4000), 3);
+    }
+
+    #[test]
+    fn ram_is_4_bits_and_echoes() {
+        let mut mbc = Mbc2::new(vec![0; 2 * ROM_BANK_SIZE as usize]);
+        assert_eq!(mbc.readb(0xA000), 0xFF);
+
+        mbc.writeb(0x0000, 0x0A);
+        mbc.writeb(0xA001, 0xAB);
+        assert_eq!(mbc.readb(0xA001), 0xFB);
+        assert_eq!(mbc.readb(0xA201), 0xFB);
+        assert_eq!(mbc.readb(0xBE01), 0xFB);
+    }
+}
@@ -1,23 +1,33 @@
+use crate::cartridge::read_rom;
 use crate::cartridge::Mbc;
 use crate::cartridge::RAM_BANK_SIZE;
 use crate::cartridge::ROM_BANK_SIZE;
+use crate::rtc::Rtc;
+use crate::rtc::RTC_DAY_HIGH;
+use crate::rtc::RTC_SECONDS;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 
 pub struct Mbc3 {
     rom: Vec<u8>,
     is_ram_enabled: bool,
     current_rom_bank: u8,
     current_ram_bank: u8,
-    ram_banks: [u8; 0x10000],
+    ram_banks: Vec<u8>,
+    rtc: Option<Rtc>,
 }
 
 impl Mbc3 {
-    pub fn new(rom: Vec<u8>) -> Box<Self> {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Box<Self> {
         Box::new(Mbc3 {
             rom,
             is_ram_enabled: false,
             current_rom_bank: 1,
             current_ram_bank: 0,
-            ram_banks: [0; 0x10000], // Max 64KB of RAM
+            ram_banks: vec![0; ram_size],
+            rtc: if has_rtc { Some(Rtc::default()) } else { None },
         })
     }
 
@@ -26,6 +36,14 @@ impl Mbc3 {
         self.is_ram_enabled = (value & 0xf) == 0xA;
     }
 
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE as usize).max(1)
+    }
+
+    fn ram_index(&self, addr: u16) -> usize {
+        (addr - 0xA000) as usize + self.current_ram_bank as usize * RAM_BANK_SIZE as usize
+    }
+
     fn change_rom_bank(&mut self, value: u8) {
         // Replace 7 lowest bits with value's ones
 
@@ -36,43 +54,131 @@ impl Mbc3 {
     }
 }
 
-// Timer less implementation of MBC3
 impl Mbc for Mbc3 {
     fn readb(&self, addr: u16) -> u8 {
         if (0xA000..=0xBFFF).contains(&addr) {
-            if self.current_ram_bank <= 3 {
-                return self.ram_banks
-                    [((addr - 0xA000) + (self.current_ram_bank as u16) * RAM_BANK_SIZE) as usize];
-            } else {
-                return 0;
+            if !self.is_ram_enabled {
+                return 0xFF;
             }
+            return match (self.current_ram_bank, &self.rtc) {
+                (0..=3, _) => *self.ram_banks.get(self.ram_index(addr)).unwrap_or(&0xFF),
+                (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.readb(self.current_ram_bank),
+                _ => 0xFF,
+            };
         }
 
         let real_addr = match addr {
             0x4000..=0x7FFF => {
-                (addr - ROM_BANK_SIZE) as u32
-                    + ((self.current_rom_bank as u32) * ROM_BANK_SIZE as u32) as u32
+                // Unused bank bits are not wired on smaller ROMs
+                let bank = self.current_rom_bank as usize % self.rom_bank_count();
+                (addr - ROM_BANK_SIZE) as usize + bank * ROM_BANK_SIZE as usize
             }
-            _ => addr as u32,
-        } as usize;
+            _ => addr as usize,
+        };
 
-        *self
-            .rom
-            .get(real_addr)
-            .expect(&format!("Cannot access cartridge memory at {:#08x}", real_addr)[..])
+        read_rom(&self.rom, real_addr)
     }
 
     fn writeb(&mut self, addr: u16, value: u8) {
         if (0xA000..0xC000).contains(&addr) {
-            // Cartridge RAM bank write
-            self.ram_banks[(addr - 0xA000) as usize] = value;
+            // Cartridge RAM bank or RTC register write
+            if !self.is_ram_enabled {
+                return;
+            }
+            let index = self.ram_index(addr);
+            match (self.current_ram_bank, &mut self.rtc) {
+                (0..=3, _) => {
+                    if let Some(byte) = self.ram_banks.get_mut(index) {
+                        *byte = value;
+                    }
+                }
+                (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.writeb(self.current_ram_bank, value),
+                _ => (),
+            }
         } else if addr < 0x2000 {
             self.toggle_ram_banking(value);
         } else if addr < 0x4000 {
             // ROM bank change
             self.change_rom_bank(value);
         } else if addr < 0x6000 {
+            // 0x00-0x03 selects a RAM bank, 0x08-0x0C a RTC register
             self.current_ram_bank = value;
+        } else if addr < 0x8000 {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.write_latch(value);
+            }
         }
     }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram_banks
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram_banks
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+impl Stateful for Mbc3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_ram_enabled);
+        state.write_u8(self.current_rom_bank);
+        state.write_u8(self.current_ram_bank);
+        state.write_bytes(&self.ram_banks);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_ram_enabled = state.read_bool()?;
+        self.current_rom_bank = state.read_u8()?;
+        self.current_ram_bank = state.read_u8()?;
+        state.read_bytes(&mut self.ram_banks)?;
+        // The RTC is part of the state since version 2
+        match &mut self.rtc {
+            Some(rtc) if state.version() >= 2 => rtc.load_state(state),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each bank starts with its own number
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE as usize];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE as usize] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn rom_bank_wraps_to_rom_size() {
+        let mut mbc = Mbc3::new(rom(4), 0, false);
+        mbc.writeb(0x2000, 0x06);
+        assert_eq!(mbc.readb(0x4000), 0x02);
+        mbc.writeb(0x2000, 0x03);
+        assert_eq!(mbc.readb(0x4000), 0x03);
+    }
+
+    #[test]
+    fn disabled_ram_reads_open_bus() {
+        let mut mbc = Mbc3::new(rom(4), 0x2000, true);
+        mbc.writeb(0x0000, 0x0A);
+        mbc.writeb(0xA000, 0x42);
+        assert_eq!(mbc.readb(0xA000), 0x42);
+
+        mbc.writeb(0x0000, 0x00);
+        assert_eq!(mbc.readb(0xA000), 0xFF);
+        mbc.writeb(0x4000, 0x08);
+        assert_eq!(mbc.readb(0xA000), 0xFF);
+    }
 }
@@ -0,0 +1,184 @@
+use crate::cartridge::read_rom;
+use crate::cartridge::Mbc;
+use crate::cartridge::RAM_BANK_SIZE;
+use crate::cartridge::ROM_BANK_SIZE;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::Bits;
+
+// On rumble cartridges, bit 3 of the RAM bank register drives the motor
+const RUMBLE_BIT: u8 = 3;
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    is_ram_enabled: bool,
+    // 9 bits, bank 0 can be mapped in 0x4000-0x7FFF
+    current_rom_bank: u16,
+    current_ram_bank: u8,
+    ram_banks: Vec<u8>,
+    has_rumble: bool,
+    is_rumbling: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Box<Self> {
+        Box::new(Mbc5 {
+            rom,
+            is_ram_enabled: false,
+            current_rom_bank: 1,
+            current_ram_bank: 0,
+            ram_banks: vec![0; ram_size],
+            has_rumble,
+            is_rumbling: false,
+        })
+    }
+
+    fn toggle_ram_banking(&mut self, value: u8) {
+        // RAM banking is only enabled if four first bits of value written is 0xA
+        self.is_ram_enabled = (value & 0xf) == 0xA;
+    }
+
+    fn change_ram_bank(&mut self, value: u8) {
+        if self.has_rumble {
+            self.is_rumbling = value.is_set(RUMBLE_BIT);
+            self.current_ram_bank = value & 0x07;
+        } else {
+            self.current_ram_bank = value & 0x0F;
+        }
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if self.ram_banks.is_empty() {
+            return None;
+        }
+        let index = (addr - 0xA000) as usize
+            + self.current_ram_bank as usize * RAM_BANK_SIZE as usize;
+        // Bank number wraps around the RAM actually present
+        Some(index % self.ram_banks.len())
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE as usize).max(1)
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn readb(&self, addr: u16) -> u8 {
+        if (0xA000..=0xBFFF).contains(&addr) {
+            return match (self.is_ram_enabled, self.ram_index(addr)) {
+                (true, Some(index)) => self.ram_banks[index],
+                _ => 0xFF,
+            };
+        }
+
+        let real_addr = match addr {
+            0x4000..=0x7FFF => {
+                // Unused bank bits are not wired on smaller ROMs
+                let bank = self.current_rom_bank as usize % self.rom_bank_count();
+                (addr - ROM_BANK_SIZE) as usize + bank * ROM_BANK_SIZE as usize
+            }
+            _ => addr as usize,
+        };
+
+        read_rom(&self.rom, real_addr)
+    }
+
+    fn writeb(&mut self, addr: u16, value: u8) {
+        if (0xA000..0xC000).contains(&addr) {
+            // Cartridge RAM bank write
+            if let (true, Some(index)) = (self.is_ram_enabled, self.ram_index(addr)) {
+                self.ram_banks[index] = value;
+            }
+        } else if addr < 0x2000 {
+            self.toggle_ram_banking(value);
+        } else if addr < 0x3000 {
+            // Lower 8 bits of the ROM bank
+            self.current_rom_bank = (self.current_rom_bank & 0x100) | value as u16;
+        } else if addr < 0x4000 {
+            // 9th bit of the ROM bank
+            self.current_rom_bank = (self.current_rom_bank & 0xFF) | ((value as u16 & 1) << 8);
+        } else if addr < 0x6000 {
+            self.change_ram_bank(value);
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram_banks
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram_banks
+    }
+
+    fn is_rumbling(&self) -> bool {
+        self.is_rumbling
+    }
+}
+
+impl Stateful for Mbc5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_ram_enabled);
+        state.write_u16(self.current_rom_bank);
+        state.write_u8(self.current_ram_bank);
+        state.write_bool(self.is_rumbling);
+        state.write_bytes(&self.ram_banks);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_ram_enabled = state.read_bool()?;
+        self.current_rom_bank = state.read_u16()?;
+        self.current_ram_bank = state.read_u8()?;
+        self.is_rumbling = state.read_bool()?;
+        state.read_bytes(&mut self.ram_banks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each bank starts with its own number
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE as usize];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE as usize] = bank as u8;
+            rom[bank * ROM_BANK_SIZE as usize + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn nine_bit_rom_bank() {
+        let mut mbc = Mbc5::new(rom(512), 0, false);
+        mbc.writeb(0x2000, 0x05);
+        mbc.writeb(0x3000, 0x01);
+        assert_eq!((mbc.readb(0x4000), mbc.readb(0x4001)), (0x05, 0x01));
+
+        // Bank 0 is not remapped to 1
+        mbc.writeb(0x2000, 0x00);
+        mbc.writeb(0x3000, 0x00);
+        assert_eq!(mbc.readb(0x4000), 0x00);
+    }
+
+    #[test]
+    fn rom_bank_wraps_to_rom_size() {
+        let mut mbc = Mbc5::new(rom(4), 0, false);
+        mbc.writeb(0x2000, 0x06);
+        assert_eq!(mbc.readb(0x4000), 0x02);
+    }
+
+    #[test]
+    fn rumble_bit_is_not_a_ram_bank() {
+        let mut mbc = Mbc5::new(rom(2), 0x8000, true);
+        mbc.writeb(0x0000, 0x0A);
+        mbc.writeb(0x4000, 0x09);
+        assert!(mbc.is_rumbling());
+        mbc.writeb(0xA000, 0x42);
+
+        mbc.writeb(0x4000, 0x01);
+        assert!(!mbc.is_rumbling());
+        assert_eq!(mbc.readb(0xA000), 0x42);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::apu::Apu;
+use crate::apu::APU_END;
+use crate::apu::APU_START;
 use crate::cartridge::Cartridge;
 use crate::joypad::Joypad;
 use crate::joypad::JOYPAD_REGISTER;
@@ -8,6 +11,13 @@ use crate::lcd::OAM_START;
 use crate::lcd::OAM_END;
 use crate::lcd::VRAM_START;
 use crate::lcd::VRAM_END;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::serial::Serial;
+use crate::serial::SERIAL_CONTROL;
+use crate::serial::SERIAL_DATA;
 use crate::timer::Timer;
 use crate::timer::DIVIDER_REGISTER;
 use crate::timer::TIMA;
@@ -15,69 +25,74 @@ use crate::timer::TMA;
 use crate::timer::TMC;
 use crate::utils::to_u16;
 use crate::utils::to_u8;
+use crate::utils::Bits;
 
 const INT_REQUEST_REGISTER: u16 = 0xFF0F; // Interupt Request Register
 const INT_ENABLED_REGISTER: u16 = 0xFFFF; // Interupt Enabled Register
+const BOOT_ROM_REGISTER: u16 = 0xFF50; // Writing to it unmaps the boot ROM
+pub const BOOT_ROM_SIZE: usize = 0x100;
 
 // TODO: make an interupt register object
-pub struct Mmu<'a> {
-    cartridge: &'a mut Cartridge,
+pub struct Mmu {
+    pub cartridge: Cartridge,
     pub memory: [u8; 0x10000],
     pub joypad: Joypad,
     pub lcd: Lcd,
     pub timer: Timer,
+    pub serial: Serial,
+    pub apu: Apu,
     pub int_request: u8, // Interupt Request Register
     pub int_enabled: u8,
+    // Overlaid on 0x0000-0x00FF until unmapped
+    boot_rom: Option<Vec<u8>>,
+    // Low-power mode entered by STOP
+    stopped: bool,
 }
 
-impl<'a> Mmu<'a> {
-    pub fn new(cartridge: &'a mut Cartridge) -> Mmu<'a> {
-        let mut mmu = Mmu {
+impl Mmu {
+    pub fn new(cartridge: Cartridge) -> Mmu {
+        Mmu {
             cartridge,
             memory: [0; 0x10000],
             joypad: Joypad::new(),
             lcd: Lcd::new(),
             timer: Timer::default(),
+            serial: Serial::default(),
+            apu: Apu::post_boot(true),
             int_request: 0,
             int_enabled: 0,
-        };
+            boot_rom: None,
+            stopped: false,
+        }
+    }
+
+    // Must be BOOT_ROM_SIZE bytes long
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    // STOP resets DIV and blanks the screen until a button is pressed
+    pub fn stop(&mut self) {
+        self.writeb(DIVIDER_REGISTER, 0);
+        self.lcd.clear_screen();
+        self.stopped = true;
+    }
 
-        mmu.memory[0xFF05] = 0x00;
-        mmu.memory[0xFF06] = 0x00;
-        mmu.memory[0xFF07] = 0x00;
-        mmu.memory[0xFF10] = 0x80;
-        mmu.memory[0xFF11] = 0xBF;
-        mmu.memory[0xFF12] = 0xF3;
-        mmu.memory[0xFF14] = 0xBF;
-        mmu.memory[0xFF16] = 0x3F;
-        mmu.memory[0xFF17] = 0x00;
-        mmu.memory[0xFF19] = 0xBF;
-        mmu.memory[0xFF1A] = 0x7F;
-        mmu.memory[0xFF1B] = 0xFF;
-        mmu.memory[0xFF1C] = 0x9F;
-        mmu.memory[0xFF1E] = 0xBF;
-        mmu.memory[0xFF20] = 0xFF;
-        mmu.memory[0xFF21] = 0x00;
-        mmu.memory[0xFF22] = 0x00;
-        mmu.memory[0xFF23] = 0xBF;
-        mmu.memory[0xFF24] = 0x77;
-        mmu.memory[0xFF25] = 0xF3;
-        mmu.memory[0xFF26] = 0xF1;
-        mmu.memory[0xFF40] = 0x91;
-        mmu.memory[0xFF42] = 0x00;
-        mmu.memory[0xFF43] = 0x00;
-        mmu.memory[0xFF45] = 0x00;
-        mmu.memory[0xFF47] = 0xFC;
-        mmu.memory[0xFF48] = 0xFF;
-        mmu.memory[0xFF49] = 0xFF;
-        mmu.memory[0xFF4A] = 0x00;
-        mmu.memory[0xFF4B] = 0x00;
-        mmu.memory[0xFFFF] = 0x00;
-
-        mmu
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Requested and enabled interupts, the upper bits of IE are
+    // writable but match no interupt
+    pub fn pending_interupts(&self) -> u8 {
+        self.int_request & self.int_enabled & 0x1F
     }
 
     pub fn readb(&self, addr: u16) -> u8 {
+        if let (Some(boot_rom), 0..=0xff) = (&self.boot_rom, addr) {
+            return boot_rom[addr as usize];
+        }
+
         match addr {
             0..=0x7fff | 0xA000..=0xBFFF => self.cartridge.readb(addr),
             DIVIDER_REGISTER | TIMA | TMA | TMC => self.timer.readb(addr),
@@ -85,7 +100,9 @@ impl<'a> Mmu<'a> {
             OAM_START..=OAM_END => self.lcd.readb(addr),
             CONTROL_REGISTER..=WINDOW_X_REGISTER => self.lcd.readb(addr),
             JOYPAD_REGISTER => self.joypad.readb(addr),
-            INT_REQUEST_REGISTER => self.int_request,
+            SERIAL_DATA | SERIAL_CONTROL => self.serial.readb(addr),
+            APU_START..=APU_END => self.apu.readb(addr),
+            INT_REQUEST_REGISTER => self.int_request | 0xE0, // Unused bits read 1
             INT_ENABLED_REGISTER => self.int_enabled,
             _ => self.memory[addr as usize],
         }
@@ -105,15 +122,29 @@ impl<'a> Mmu<'a> {
                 self.memory[addr as usize] = value;
                 self.writeb(addr - 0x2000, value)
             } // ECHO RAM
-            DIVIDER_REGISTER | TIMA | TMA | TMC => self.timer.writeb(addr, value),
+            DIVIDER_REGISTER => {
+                // Resetting DIV can make its bit 4 fall
+                let divider = self.timer.readb(DIVIDER_REGISTER);
+                self.timer.writeb(addr, value);
+                self.clock_frame_sequencer(divider);
+            }
+            TIMA | TMA | TMC => self.timer.writeb(addr, value),
             0xfea0..=0xfeff => (), // Restricted
             0xff46 => self.do_dma(value),
             VRAM_START..=VRAM_END => self.lcd.writeb(addr, value),
             OAM_START..=OAM_END => self.lcd.writeb(addr, value),
             CONTROL_REGISTER..=WINDOW_X_REGISTER => self.lcd.writeb(addr, value),
             JOYPAD_REGISTER => self.joypad.writeb(addr, value),
-            INT_REQUEST_REGISTER => self.int_request = value,
+            SERIAL_DATA | SERIAL_CONTROL => self.serial.writeb(addr, value),
+            APU_START..=APU_END => self.apu.writeb(addr, value),
+            INT_REQUEST_REGISTER => self.int_request = value & 0x1F,
             INT_ENABLED_REGISTER => self.int_enabled = value,
+            // Cannot be mapped back until power off
+            BOOT_ROM_REGISTER => {
+                if value != 0 {
+                    self.boot_rom = None;
+                }
+            }
             n => self.memory[n as usize] = value,
         };
     }
@@ -133,14 +164,84 @@ impl<'a> Mmu<'a> {
         }
     }
 
+    // The APU frame sequencer steps when bit 4 of DIV falls
+    fn clock_frame_sequencer(&mut self, previous_divider: u8) {
+        let divider = self.timer.readb(DIVIDER_REGISTER);
+        if previous_divider.is_set(4) && divider.is_unset(4) {
+            self.apu.clock_frame_sequencer();
+        }
+    }
+
     pub fn update(&mut self, cycles: u32) {
-        self.timer.update(cycles);
-        self.int_request |= self.timer.int_request;
-        self.timer.int_request = 0;
-        self.lcd.update_graphics(cycles);
-        self.int_request |= self.lcd.int_request;
-        self.lcd.int_request = 0;
+        self.cartridge.update(cycles);
+        if self.stopped && self.joypad.is_input_low() {
+            self.stopped = false;
+        }
+
+        // DIV and the LCD are halted while stopped
+        if !self.stopped {
+            let divider = self.timer.readb(DIVIDER_REGISTER);
+            self.timer.update(cycles);
+            self.clock_frame_sequencer(divider);
+            self.int_request |= self.timer.int_request;
+            self.timer.int_request = 0;
+        }
+        self.apu.update(cycles);
+        self.serial.update(cycles);
+        self.int_request |= self.serial.int_request;
+        self.serial.int_request = 0;
+        if !self.stopped {
+            self.lcd.update_graphics(cycles);
+            self.int_request |= self.lcd.int_request;
+            self.lcd.int_request = 0;
+        }
         self.int_request |= self.joypad.int_request;
         self.joypad.int_request = 0;
     }
 }
+
+impl Stateful for Mmu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cartridge.save_state(state);
+        state.write_bytes(&self.memory);
+        self.joypad.save_state(state);
+        self.lcd.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.apu.save_state(state);
+        state.write_u8(self.int_request);
+        state.write_u8(self.int_enabled);
+        state.write_bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = &self.boot_rom {
+            state.write_bytes(boot_rom);
+        }
+        state.write_bool(self.stopped);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cartridge.load_state(state)?;
+        state.read_bytes(&mut self.memory)?;
+        self.joypad.load_state(state)?;
+        self.lcd.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        // Sound was not emulated before version 6, it restarts silent
+        if state.version() >= 6 {
+            self.apu.load_state(state)?;
+        } else {
+            self.apu = Apu::post_boot(false);
+        }
+        self.int_request = state.read_u8()? & 0x1F;
+        self.int_enabled = state.read_u8()?;
+        // Boot ROM mapping was not saved before version 5
+        self.boot_rom = None;
+        if state.version() >= 5 && state.read_bool()? {
+            let mut boot_rom = vec![0; BOOT_ROM_SIZE];
+            state.read_bytes(&mut boot_rom)?;
+            self.boot_rom = Some(boot_rom);
+        }
+        // STOP was a NOP before version 7
+        self.stopped = state.version() >= 7 && state.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::apu::Apu;
+use crate::mmu::Mmu;
+use crate::registers::Registers;
+
+// Header fields the boot ROMs look at
+const LOGO_START: u16 = 0x104;
+const LOGO_SIZE: u16 = 48;
+const TITLE_START: u16 = 0x134;
+const TITLE_END: u16 = 0x144;
+const CGB_FLAG: u16 = 0x143;
+const NEW_LICENSEE: u16 = 0x144;
+const OLD_LICENSEE: u16 = 0x14B;
+const HEADER_CHECKSUM: u16 = 0x14D;
+
+const WRAM_START: usize = 0xC000;
+const WRAM_END: usize = 0xE000;
+
+// Registered trademark drawn after the logo by the DMG boot ROM
+const TRADEMARK_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Model {
+    Dmg0,
+    #[default]
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "sgb2" => Ok(Model::Sgb2),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!("Unknown model {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Sgb2 => "SGB2",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn registers(a: u8, f: u8, bc: u16, de: u16, hl: u16) -> Registers {
+    // Every boot ROM jumps to 0x100 with the same stack
+    let mut registers = Registers {
+        a,
+        f,
+        pc: 0x100,
+        sp: 0xFFFE,
+        ..Registers::power_on()
+    };
+    registers.set_bc(bc);
+    registers.set_de(de);
+    registers.set_hl(hl);
+    registers
+}
+
+// Flags set by the INC B the AGB boot ROM ends with
+fn inc_flags(value: u8) -> u8 {
+    let zero = if value == 0 { 0x80 } else { 0 };
+    let half_carry = if value & 0x0F == 0 { 0x20 } else { 0 };
+    zero | half_carry
+}
+
+impl Model {
+    fn is_color(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    // CPU registers left by the boot ROM. Games tell models apart with A and B.
+    fn post_boot_registers(&self, mmu: &Mmu) -> Registers {
+        // DMG boot ROMs compare the header checksum, leaving H and C set if not 0
+        let checksum_flags = if mmu.readb(HEADER_CHECKSUM) == 0 { 0x80 } else { 0xB0 };
+
+        match self {
+            Model::Dmg0 => registers(0x01, 0x00, 0xFF13, 0x00C1, 0x8403),
+            Model::Dmg => registers(0x01, checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::Mgb => registers(0xFF, checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::Sgb => registers(0x01, 0x00, 0x0014, 0x0000, 0xC060),
+            Model::Sgb2 => registers(0xFF, 0x00, 0x0014, 0x0000, 0xC060),
+            Model::Cgb | Model::Agb => {
+                let (b, de, hl) = if mmu.readb(CGB_FLAG) & 0x80 != 0 {
+                    (0x00, 0xFF56, 0x000D)
+                } else {
+                    // Compatibility mode, B is the title sum of Nintendo games
+                    // and picks the palette
+                    let b = if is_nintendo(mmu) { title_sum(mmu) } else { 0 };
+                    let hl = if b == 0x43 || b == 0x58 { 0x991A } else { 0x007C };
+                    (b, 0x0008, hl)
+                };
+
+                if *self == Model::Agb {
+                    let b = b.wrapping_add(1);
+                    registers(0x11, inc_flags(b), (b as u16) << 8, de, hl)
+                } else {
+                    registers(0x11, 0x80, (b as u16) << 8, de, hl)
+                }
+            }
+        }
+    }
+
+    // Internal 16 bits counter behind DIV when the game starts.
+    // SGB and CGB boot times vary, their values are approximations.
+    fn post_boot_divider(&self, mmu: &Mmu) -> u16 {
+        match self {
+            Model::Dmg0 => 0x182C,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => 0xD85C,
+            Model::Cgb | Model::Agb if mmu.readb(CGB_FLAG) & 0x80 != 0 => 0x1EA0,
+            Model::Cgb | Model::Agb => 0x267C,
+        }
+    }
+
+    // I/O registers left by the boot ROM. Sound comes from Apu::post_boot
+    // and DIV from post_boot_divider.
+    fn post_boot_io(&self) -> [(u16, u8); 17] {
+        let serial_control = if self.is_color() { 0x7F } else { 0x7E };
+        [
+            (0xFF00, 0xCF), // P1
+            (0xFF02, serial_control), // SC
+            (0xFF05, 0x00), // TIMA
+            (0xFF06, 0x00), // TMA
+            (0xFF07, 0xF8), // TAC
+            (0xFF0F, 0xE1), // IF: V-Blank still requested
+            (0xFF40, 0x91), // LCDC
+            (0xFF41, 0x85), // STAT
+            (0xFF42, 0x00), // SCY
+            (0xFF43, 0x00), // SCX
+            (0xFF45, 0x00), // LYC
+            (0xFF47, 0xFC), // BGP
+            (0xFF48, 0xFF), // OBP0
+            (0xFF49, 0xFF), // OBP1
+            (0xFF4A, 0x00), // WY
+            (0xFF4B, 0x00), // WX
+            (0xFFFF, 0x00), // IE
+        ]
+    }
+
+    // Sets the machine as the boot ROM of this model leaves it
+    pub(crate) fn apply_post_boot_state(&self, registers: &mut Registers, mmu: &mut Mmu) {
+        *registers = self.post_boot_registers(mmu);
+        mmu.timer.set_divider_counter(self.post_boot_divider(mmu));
+        for (addr, value) in self.post_boot_io() {
+            mmu.writeb(addr, value);
+        }
+
+        // SGB boot ROM does not play the sound, channel 1 is left off
+        if matches!(self, Model::Sgb | Model::Sgb2) {
+            mmu.apu = Apu::post_boot(false);
+        }
+
+        fill_wram(mmu);
+        if !self.is_color() && !matches!(self, Model::Sgb | Model::Sgb2) {
+            draw_logo(mmu);
+        }
+    }
+}
+
+fn is_nintendo(mmu: &Mmu) -> bool {
+    match mmu.readb(OLD_LICENSEE) {
+        0x01 => true,
+        0x33 => mmu.readb(NEW_LICENSEE) == b'0' && mmu.readb(NEW_LICENSEE + 1) == b'1',
+        _ => false,
+    }
+}
+
+fn title_sum(mmu: &Mmu) -> u8 {
+    (TITLE_START..TITLE_END).fold(0u8, |sum, addr| sum.wrapping_add(mmu.readb(addr)))
+}
+
+// Work RAM is not cleared at power on and holds garbage. A fixed
+// xorshift sequence keeps runs reproducible.
+fn fill_wram(mmu: &mut Mmu) {
+    let mut seed: u32 = 0x6B8B4567;
+    for byte in mmu.memory[WRAM_START..WRAM_END].iter_mut() {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        *byte = seed as u8;
+    }
+}
+
+// Each bit of a logo nibble becomes 2 pixels
+fn double_bits(nibble: u8) -> u8 {
+    (0..4).fold(0, |byte, bit| {
+        if nibble & (1 << bit) != 0 {
+            byte | (0b11 << (bit * 2))
+        } else {
+            byte
+        }
+    })
+}
+
+// VRAM as left by the DMG boot ROM: the cartridge logo scaled 2x in
+// tiles 1 to 24, the trademark in tile 25 and the tilemap showing them
+fn draw_logo(mmu: &mut Mmu) {
+    let mut addr = 0x8010;
+    for offset in 0..LOGO_SIZE {
+        let byte = mmu.readb(LOGO_START + offset);
+        for nibble in [byte >> 4, byte & 0x0F] {
+            // Every row is drawn twice, second bitplane stays empty
+            let row = double_bits(nibble);
+            mmu.writeb(addr, row);
+            mmu.writeb(addr + 2, row);
+            addr += 4;
+        }
+    }
+
+    for row in TRADEMARK_TILE {
+        mmu.writeb(addr, row);
+        addr += 2;
+    }
+
+    for tile in 1..=12u8 {
+        mmu.writeb(0x9903 + tile as u16, tile);
+        mmu.writeb(0x9923 + tile as u16, tile + 12);
+    }
+    mmu.writeb(0x9910, 25);
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::gameboy::CLOCK_SPEED;
+use crate::gameboy::FRAME_CYCLES;
+
+// About 59.73 frames per second
+pub const FRAME_RATE: f64 = CLOCK_SPEED as f64 / FRAME_CYCLES as f64;
+
+// Sound is stretched by at most 0.5%, too little to hear the pitch change
+pub const MAX_RATE_DELTA: f64 = 0.005;
+
+// Left over of a sleep is spent spinning, as sleeps overshoot
+const SPIN_MARGIN: Duration = Duration::from_millis(1);
+// Further behind, the clock gives up catching up
+const MAX_LATE_FRAMES: u32 = 4;
+
+// What the frontend waits on before running the next frame
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Pacing {
+    // The sound card consuming the queued samples
+    #[default]
+    Audio,
+    // The duration of a frame on the host clock
+    Clock,
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "audio" => Ok(Pacing::Audio),
+            "clock" => Ok(Pacing::Clock),
+            _ => Err(format!("Unknown pacing {}, expected audio or clock", s)),
+        }
+    }
+}
+
+impl fmt::Display for Pacing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pacing::Audio => write!(f, "audio"),
+            Pacing::Clock => write!(f, "clock"),
+        }
+    }
+}
+
+// Waits for frames to be due at the rate of the real console,
+// without drifting from the host clock
+pub struct FrameClock {
+    frame: Duration,
+    next: Instant,
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        FrameClock {
+            frame: Duration::from_secs_f64(1.0 / FRAME_RATE),
+            next: Instant::now(),
+        }
+    }
+}
+
+impl FrameClock {
+    // Next frame is due now, after a pause or a pacing change
+    pub fn reset(&mut self) {
+        self.next = Instant::now();
+    }
+
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if now > self.next + self.frame * MAX_LATE_FRAMES {
+            self.next = now;
+        }
+
+        if let Some(remaining) = self.next.checked_duration_since(now) {
+            if remaining > SPIN_MARGIN {
+                std::thread::sleep(remaining - SPIN_MARGIN);
+            }
+            while Instant::now() < self.next {
+                std::hint::spin_loop();
+            }
+        }
+        self.next += self.frame;
+    }
+}
+
+// Dynamic rate control: output frames to produce per input frame so that
+// an audio buffer filled at `fill` (0 empty, 1 full) drifts back to half full
+pub fn rate_ratio(fill: f64) -> f64 {
+    1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill.clamp(0.0, 1.0))
+}
+
+// Linear interpolation of interleaved stereo samples
+#[derive(Default)]
+pub struct Resampler {
+    // In input frames, from the first frame of the next input. The
+    // last frame of the previous input is at -1.
+    position: f64,
+    previous: [f32; 2],
+}
+
+impl Resampler {
+    pub fn process(&mut self, input: &[f32], ratio: f64) -> Vec<f32> {
+        let frames = (input.len() / 2) as isize;
+        let previous = self.previous;
+        let frame = |index: isize| match index {
+            -1 => previous,
+            _ => [input[index as usize * 2], input[index as usize * 2 + 1]],
+        };
+
+        let step = 1.0 / ratio;
+        let mut output = Vec::with_capacity((input.len() as f64 * ratio) as usize + 2);
+        while self.position < (frames - 1) as f64 {
+            let index = self.position.floor() as isize;
+            let t = (self.position - index as f64) as f32;
+            let (a, b) = (frame(index), frame(index + 1));
+            output.push(a[0] + (b[0] - a[0]) * t);
+            output.push(a[1] + (b[1] - a[1]) * t);
+            self.position += step;
+        }
+
+        self.previous = frame(frames - 1);
+        self.position -= frames as f64;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_follows_fill_level() {
+        assert_eq!(rate_ratio(0.5), 1.0);
+        assert_eq!(rate_ratio(0.0), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(rate_ratio(2.0), 1.0 - MAX_RATE_DELTA);
+    }
+
+    #[test]
+    fn resampler_keeps_samples_at_same_rate() {
+        let input: Vec<f32> = (0..200).map(|i| i as f32).collect();
+        let mut resampler = Resampler::default();
+
+        let mut output = Vec::new();
+        for chunk in input.chunks(50) {
+            output.extend(resampler.process(chunk, 1.0));
+        }
+        // Lags a frame behind
+        assert_eq!(output, input[..198]);
+    }
+
+    #[test]
+    fn resampler_stretches_sound() {
+        let input = vec![0.0; 2 * 10000];
+        let mut resampler = Resampler::default();
+
+        let mut frames = 0;
+        for chunk in input.chunks(800) {
+            frames += resampler.process(chunk, 1.01).len() / 2;
+        }
+        assert!((10099..=10101).contains(&frames), "{}", frames);
+    }
+}
@@ -1,3 +1,7 @@
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 use crate::utils::Bits;
 
 pub struct Registers {
@@ -13,9 +17,11 @@ pub struct Registers {
     pub sp: u16,
 }
 
-impl Default for Registers {
-    fn default() -> Self {
-        let mut registers = Registers {
+impl Registers {
+    // Before the boot ROM runs, everything is cleared. Games start
+    // with the state of GameBoy::with_model instead.
+    pub fn power_on() -> Self {
+        Registers {
             a: 0,
             b: 0,
             c: 0,
@@ -24,14 +30,9 @@ impl Default for Registers {
             f: 0,
             h: 0,
             l: 0,
-            pc: 0x100,
-            sp: 0xfffe,
-        };
-        registers.set_af(0x01b0);
-        registers.set_bc(0x13);
-        registers.set_de(0xd8);
-        registers.set_hl(0x14d);
-        registers
+            pc: 0,
+            sp: 0,
+        }
     }
 }
 
@@ -119,4 +120,25 @@ impl Registers {
     pub fn get_c(&self) -> bool {
         self.f.is_set(4)
     }
-}
\ No newline at end of file
+}
+
+impl Stateful for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.af());
+        state.write_u16(self.bc());
+        state.write_u16(self.de());
+        state.write_u16(self.hl());
+        state.write_u16(self.pc);
+        state.write_u16(self.sp);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.set_af(state.read_u16()?);
+        self.set_bc(state.read_u16()?);
+        self.set_de(state.read_u16()?);
+        self.set_hl(state.read_u16()?);
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::gameboy::CLOCK_SPEED;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::Bits;
+
+// RTC registers, selected by writing to 0x4000-0x5FFF
+pub const RTC_SECONDS: u8 = 0x08;
+pub const RTC_MINUTES: u8 = 0x09;
+pub const RTC_HOURS: u8 = 0x0A;
+pub const RTC_DAY_LOW: u8 = 0x0B;
+// Bit 0: day counter bit 8
+// Bit 6: halt
+// Bit 7: day counter carry
+pub const RTC_DAY_HIGH: u8 = 0x0C;
+
+const HALT_BIT: u8 = 6;
+const CARRY_BIT: u8 = 7;
+
+// Size of the RTC data appended to .sav files, shared by most emulators:
+// 5 live registers and 5 latched registers as u32, then a u64 timestamp
+pub const FOOTER_SIZE: usize = 48;
+// Older layout with a 32 bits timestamp
+const LEGACY_FOOTER_SIZE: usize = 44;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RtcClock {
+    // Counts emulated cycles, time stops with the emulator
+    Emulated,
+    // Follows the host wall clock
+    Host,
+}
+
+#[derive(Copy, Clone, Default)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    carry: bool,
+}
+
+impl RtcRegisters {
+    fn readb(&self, register: u8) -> u8 {
+        match register {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => self.hours,
+            RTC_DAY_LOW => self.days as u8,
+            RTC_DAY_HIGH => self.day_high(),
+            _ => 0xFF,
+        }
+    }
+
+    fn writeb(&mut self, register: u8, value: u8) {
+        match register {
+            RTC_SECONDS => self.seconds = value & 0x3F,
+            RTC_MINUTES => self.minutes = value & 0x3F,
+            RTC_HOURS => self.hours = value & 0x1F,
+            RTC_DAY_LOW => self.days = (self.days & 0x100) | value as u16,
+            RTC_DAY_HIGH => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 1) << 8);
+                self.halted = value.is_set(HALT_BIT);
+                self.carry = value.is_set(CARRY_BIT);
+            }
+            _ => (),
+        }
+    }
+
+    fn day_high(&self) -> u8 {
+        (self.days >> 8) as u8 & 1 | (self.halted as u8) << HALT_BIT | (self.carry as u8) << CARRY_BIT
+    }
+
+    fn is_in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    // One second. Counters set out of range count up to their bit
+    // width and wrap to 0 without carrying, like the real chip.
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halted {
+            return;
+        }
+
+        let mut seconds = seconds;
+        while seconds > 0 && !self.is_in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total = self.seconds as u64
+            + 60 * (self.minutes as u64 + 60 * (self.hours as u64 + 24 * self.days as u64))
+            + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+
+        let days = total / 86400;
+        if days >= 512 {
+            self.carry = true;
+        }
+        self.days = (days % 512) as u16;
+    }
+
+    fn write_footer(&self, footer: &mut Vec<u8>) {
+        for register in RTC_SECONDS..=RTC_DAY_HIGH {
+            footer.extend_from_slice(&(self.readb(register) as u32).to_le_bytes());
+        }
+    }
+
+    fn read_footer(&mut self, footer: &[u8]) {
+        for (register, value) in (RTC_SECONDS..=RTC_DAY_HIGH).zip(footer.chunks(4)) {
+            self.writeb(register, value[0]);
+        }
+    }
+}
+
+impl Stateful for RtcRegisters {
+    fn save_state(&self, state: &mut StateWriter) {
+        for register in RTC_SECONDS..=RTC_DAY_HIGH {
+            state.write_u8(self.readb(register));
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for register in RTC_SECONDS..=RTC_DAY_HIGH {
+            self.writeb(register, state.read_u8()?);
+        }
+        Ok(())
+    }
+}
+
+// Real time clock of MBC3 cartridges
+pub struct Rtc {
+    clock: RtcClock,
+    live: RtcRegisters,
+    latched: RtcRegisters,
+    // Cycles elapsed in the current second
+    cycles: u32,
+    // Last value written to the latch register
+    latch_value: u8,
+    // Host time the registers were last brought up to date with
+    host_sync: u64,
+}
+
+fn host_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Rtc {
+            clock: RtcClock::Emulated,
+            live: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            cycles: 0,
+            latch_value: 0xFF,
+            host_sync: host_now(),
+        }
+    }
+}
+
+impl Rtc {
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.sync_host();
+        self.clock = clock;
+    }
+
+    pub fn update(&mut self, cycles: u32) {
+        if self.clock != RtcClock::Emulated || self.live.halted {
+            return;
+        }
+
+        self.cycles += cycles;
+        while self.cycles >= CLOCK_SPEED {
+            self.cycles -= CLOCK_SPEED;
+            self.live.tick();
+        }
+    }
+
+    // Brings the registers up to date with the host clock
+    fn sync_host(&mut self) {
+        let now = host_now();
+        if self.clock == RtcClock::Host {
+            self.live.advance(now.saturating_sub(self.host_sync));
+        }
+        self.host_sync = now;
+    }
+
+    // Games read the latched copy, only writing 0 then 1 updates it
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_value == 0 && value == 1 {
+            self.sync_host();
+            self.latched = self.live;
+        }
+        self.latch_value = value;
+    }
+
+    pub fn readb(&self, register: u8) -> u8 {
+        self.latched.readb(register)
+    }
+
+    pub fn writeb(&mut self, register: u8, value: u8) {
+        self.sync_host();
+        if register == RTC_SECONDS {
+            self.cycles = 0;
+        }
+        self.live.writeb(register, value);
+    }
+
+    pub fn save_footer(&mut self) -> Vec<u8> {
+        self.sync_host();
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        self.live.write_footer(&mut footer);
+        self.latched.write_footer(&mut footer);
+        footer.extend_from_slice(&self.host_sync.to_le_bytes());
+        footer
+    }
+
+    // Restores the clock. A clock following the host catches up
+    // with the time the emulator was not running.
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        let timestamp = match footer.len() {
+            FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            LEGACY_FOOTER_SIZE => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+            _ => return,
+        };
+
+        self.live.read_footer(&footer[0..20]);
+        self.latched.read_footer(&footer[20..40]);
+
+        let now = host_now();
+        if self.clock == RtcClock::Host {
+            self.live.advance(now.saturating_sub(timestamp));
+        }
+        self.host_sync = now;
+    }
+}
+
+impl Stateful for Rtc {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.live.save_state(state);
+        self.latched.save_state(state);
+        state.write_u32(self.cycles);
+        state.write_u8(self.latch_value);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.live.load_state(state)?;
+        self.latched.load_state(state)?;
+        self.cycles = state.read_u32()?;
+        self.latch_value = state.read_u8()?;
+        self.host_sync = host_now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers(seconds: u8, minutes: u8, hours: u8, days: u16) -> RtcRegisters {
+        RtcRegisters {
+            seconds,
+            minutes,
+            hours,
+            days,
+            ..RtcRegisters::default()
+        }
+    }
+
+    #[test]
+    fn tick_carries_into_days() {
+        let mut rtc = registers(59, 59, 23, 511);
+        rtc.tick();
+        assert_eq!((rtc.seconds, rtc.minutes, rtc.hours, rtc.days), (0, 0, 0, 0));
+        assert!(rtc.carry);
+    }
+
+    #[test]
+    fn out_of_range_seconds_wrap_without_carry() {
+        let mut rtc = registers(63, 10, 0, 0);
+        rtc.tick();
+        assert_eq!((rtc.seconds, rtc.minutes), (0, 10));
+    }
+
+    #[test]
+    fn advance_matches_ticks() {
+        let mut stepped = registers(62, 58, 23, 510);
+        let mut advanced = stepped;
+        for _ in 0..200_000 {
+            stepped.tick();
+        }
+        advanced.advance(200_000);
+
+        assert_eq!(stepped.seconds, advanced.seconds);
+        assert_eq!(stepped.minutes, advanced.minutes);
+        assert_eq!(stepped.hours, advanced.hours);
+        assert_eq!(stepped.days, advanced.days);
+        assert_eq!(stepped.carry, advanced.carry);
+    }
+
+    #[test]
+    fn halted_clock_does_not_advance() {
+        let mut rtc = registers(0, 0, 0, 0);
+        rtc.halted = true;
+        rtc.advance(1000);
+        assert_eq!(rtc.seconds, 0);
+    }
+
+    #[test]
+    fn latch_needs_zero_then_one() {
+        let mut rtc = Rtc::default();
+        rtc.writeb(RTC_MINUTES, 12);
+        rtc.write_latch(1);
+        assert_eq!(rtc.readb(RTC_MINUTES), 0);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.readb(RTC_MINUTES), 12);
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        let mut rtc = Rtc::default();
+        rtc.writeb(RTC_HOURS, 5);
+        rtc.writeb(RTC_DAY_HIGH, 1 << HALT_BIT | 1);
+        let footer = rtc.save_footer();
+        assert_eq!(footer.len(), FOOTER_SIZE);
+
+        let mut loaded = Rtc::default();
+        loaded.load_footer(&footer);
+        assert_eq!(loaded.live.hours, 5);
+        assert_eq!(loaded.live.days, 256);
+        assert!(loaded.live.halted);
+    }
+
+    #[test]
+    fn only_host_clock_catches_up() {
+        let mut footer = Rtc::default().save_footer();
+        // Saved an hour ago
+        let saved = host_now() - 3600;
+        footer[40..48].copy_from_slice(&saved.to_le_bytes());
+
+        let mut emulated = Rtc::default();
+        emulated.load_footer(&footer);
+        assert_eq!(emulated.live.hours, 0);
+
+        let mut host = Rtc::default();
+        host.set_clock(RtcClock::Host);
+        host.load_footer(&footer);
+        assert_eq!(host.live.hours, 1);
+    }
+}
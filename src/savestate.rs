@@ -0,0 +1,271 @@
+use std::fmt;
+
+// Save state layout, all values little endian:
+// magic (4 bytes) | version (u16) | ROM hash (u64) | payload length (u32)
+// | payload CRC32 (u32) | payload
+//
+// Bump STATE_VERSION whenever a component changes what it saves.
+// Older versions are either migrated in load_state or rejected.
+const MAGIC: &[u8; 4] = b"GBST";
+pub const STATE_VERSION: u16 = 9;
+const HEADER_SIZE: usize = 4 + 2 + 8 + 4 + 4;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    WrongRom,
+    ChecksumMismatch,
+    UnexpectedEnd,
+    Corrupted(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a gb-rs save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported (expected {})",
+                version, STATE_VERSION
+            ),
+            StateError::WrongRom => write!(f, "save state was made with another ROM"),
+            StateError::ChecksumMismatch => write!(f, "save state checksum does not match"),
+            StateError::UnexpectedEnd => write!(f, "save state is truncated"),
+            StateError::Corrupted(what) => write!(f, "save state is corrupted: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Implemented by every component holding emulation state
+pub trait Stateful {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed so a size change is detected on load
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Wraps the payload with the header
+    pub fn finish(self, rom_hash: u64) -> Vec<u8> {
+        let mut state = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        state.extend_from_slice(MAGIC);
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(&rom_hash.to_le_bytes());
+        state.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        state.extend_from_slice(&crc32(&self.data).to_le_bytes());
+        state.extend_from_slice(&self.data);
+        state
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header and returns a reader over the payload
+    pub fn new(state: &'a [u8], rom_hash: u64) -> Result<Self, StateError> {
+        if state.len() < HEADER_SIZE || &state[0..4] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let mut header = StateReader {
+            data: &state[4..HEADER_SIZE],
+            position: 0,
+            version: 0,
+        };
+        let version = header.read_u16()?;
+        let hash = header.read_u64()?;
+        let length = header.read_u32()? as usize;
+        let checksum = header.read_u32()?;
+
+        if version == 0 || version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if hash != rom_hash {
+            return Err(StateError::WrongRom);
+        }
+
+        let payload = &state[HEADER_SIZE..];
+        if payload.len() != length {
+            return Err(StateError::UnexpectedEnd);
+        }
+        if crc32(payload) != checksum {
+            return Err(StateError::ChecksumMismatch);
+        }
+
+        Ok(StateReader {
+            data: payload,
+            position: 0,
+            version,
+        })
+    }
+
+    // Version the state was saved with, for migrations
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position + count;
+        if end > self.data.len() {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupted("invalid boolean")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // Fills bytes, which must have the size it was saved with
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        let length = self.read_u32()? as usize;
+        if length != bytes.len() {
+            return Err(StateError::Corrupted("memory size mismatch"));
+        }
+        bytes.copy_from_slice(self.take(length)?);
+        Ok(())
+    }
+
+    // Fails if some data was not consumed
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.position != self.data.len() {
+            return Err(StateError::Corrupted("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    // Bitwise CRC-32 (IEEE), states are small enough to skip the table
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::default();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_bytes(&[1, 2, 3]);
+        let state = writer.finish(42);
+
+        let mut reader = StateReader::new(&state, 42).unwrap();
+        assert_eq!(reader.version(), STATE_VERSION);
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789ABCDE));
+        let mut bytes = [0; 3];
+        assert_eq!(reader.read_bytes(&mut bytes), Ok(()));
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let mut writer = StateWriter::default();
+        writer.write_u32(0xDEADBEEF);
+        let state = writer.finish(42);
+
+        assert!(matches!(StateReader::new(&state[..10], 42), Err(StateError::NotAState)));
+        assert!(matches!(StateReader::new(&state, 43), Err(StateError::WrongRom)));
+
+        let mut corrupted = state.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            StateReader::new(&corrupted, 42),
+            Err(StateError::ChecksumMismatch)
+        ));
+
+        let mut future = state.clone();
+        future[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            StateReader::new(&future, 42),
+            Err(StateError::UnsupportedVersion(_))
+        ));
+
+        let mut reader = StateReader::new(&state, 42).unwrap();
+        assert_eq!(reader.read_u64(), Err(StateError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::lcd::Color;
+use crate::lcd::SCREEN_HEIGHT;
+use crate::lcd::SCREEN_WIDTH;
+use crate::utils::fnv1a;
+
+pub type Screen = [[Color; SCREEN_HEIGHT]; SCREEN_WIDTH];
+
+// Row major RGB bytes, as expected by image formats
+pub fn to_rgb(screen: &Screen) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+    for y in 0..SCREEN_HEIGHT {
+        for column in screen.iter() {
+            let (r, g, b) = column[y].rgb();
+            data.extend_from_slice(&[r, g, b]);
+        }
+    }
+    data
+}
+
+pub fn framebuffer_hash(screen: &Screen) -> u64 {
+    fnv1a(&to_rgb(screen))
+}
+
+pub fn save_png(screen: &Screen, path: &Path) -> std::io::Result<()> {
+    write_rgb_png(&to_rgb(screen), path)
+}
+
+fn write_rgb_png(data: &[u8], path: &Path) -> std::io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    Ok(())
+}
+
+// Canonical 4-shade image: one byte per pixel, row major,
+// 0 for white up to 3 for black
+pub fn to_shades(screen: &Screen) -> Vec<u8> {
+    let mut shades = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+    for y in 0..SCREEN_HEIGHT {
+        for column in screen.iter() {
+            shades.push(column[y].shade());
+        }
+    }
+    shades
+}
+
+fn shade_from_rgb(r: u8, g: u8, b: u8) -> u8 {
+    // Reference images do not agree on a palette, so the
+    // brightness is rounded to the nearest of the 4 shades
+    let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+    3 - ((luma * 3 + 127) / 255) as u8
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// Loads a reference screenshot as a canonical 4-shade image
+pub fn load_png_shades(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data)?;
+    if info.width as usize != SCREEN_WIDTH || info.height as usize != SCREEN_HEIGHT {
+        return Err(invalid_data(format!(
+            "{} is {}x{}, expected {}x{}",
+            path.display(),
+            info.width,
+            info.height,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT
+        )));
+    }
+
+    let channels = info.color_type.samples();
+    let shades = data[..info.buffer_size()]
+        .chunks(info.line_size)
+        .flat_map(|line| line.chunks(channels))
+        .map(|pixel| match pixel {
+            // Grayscale, with or without alpha
+            [luma] | [luma, _] => shade_from_rgb(*luma, *luma, *luma),
+            [r, g, b, ..] => shade_from_rgb(*r, *g, *b),
+            _ => 0,
+        })
+        .collect();
+
+    Ok(shades)
+}
+
+pub fn count_differences(actual: &[u8], expected: &[u8]) -> usize {
+    actual
+        .iter()
+        .zip(expected.iter())
+        .filter(|(a, e)| a != e)
+        .count()
+}
+
+// Faded copy of the actual image with mismatching pixels in red
+pub fn save_diff_png(actual: &[u8], expected: &[u8], path: &Path) -> std::io::Result<()> {
+    let mut data = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+    for (a, e) in actual.iter().zip(expected.iter()) {
+        if a == e {
+            let grey = 255 - a * 24;
+            data.extend_from_slice(&[grey, grey, grey]);
+        } else {
+            data.extend_from_slice(&[255, 0, 0]);
+        }
+    }
+
+    write_rgb_png(&data, path)
+}
@@ -0,0 +1,145 @@
+use std::io::Write;
+
+use crate::cpu::SERIAL_INTERUPT;
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
+use crate::utils::Bits;
+
+pub const SERIAL_DATA: u16 = 0xFF01; // SB
+pub const SERIAL_CONTROL: u16 = 0xFF02; // SC
+
+// Serial control bits
+const TRANSFER_START_BIT: u8 = 7;
+const INTERNAL_CLOCK_BIT: u8 = 0;
+
+// Internal clock runs at 8192 Hz, so one bit is shifted every 512 cycles
+const BIT_CYCLES: u32 = 512;
+
+// Something plugged in the link port
+pub trait SerialDevice {
+    // Called once a whole byte has been shifted out
+    // Returns the byte shifted in by the device at the same time
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    // True if the device provides the clock when the Game Boy
+    // selects the external clock
+    fn drives_clock(&self) -> bool {
+        false
+    }
+}
+
+// Prints every byte sent, which is how blargg test ROMs report results
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+        // Nothing connected on the other end, line is pulled up
+        0xFF
+    }
+}
+
+#[derive(Default)]
+pub struct Serial {
+    data: u8,
+    control: u8,
+    transfer_cycles: u32,
+    bits_shifted: u8,
+    device: Option<Box<dyn SerialDevice>>,
+    pub int_request: u8,
+}
+
+impl Serial {
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    fn is_transferring(&self) -> bool {
+        self.control.is_set(TRANSFER_START_BIT)
+    }
+
+    fn is_clock_running(&self) -> bool {
+        if self.control.is_set(INTERNAL_CLOCK_BIT) {
+            return true;
+        }
+
+        // External clock, only a device can shift the bits
+        match &self.device {
+            Some(device) => device.drives_clock(),
+            None => false,
+        }
+    }
+
+    pub fn update(&mut self, cycles: u32) {
+        if !self.is_transferring() || !self.is_clock_running() {
+            return;
+        }
+
+        self.transfer_cycles += cycles;
+        while self.transfer_cycles >= BIT_CYCLES && self.is_transferring() {
+            self.transfer_cycles -= BIT_CYCLES;
+            self.bits_shifted += 1;
+
+            if self.bits_shifted == 8 {
+                self.end_transfer();
+            }
+        }
+    }
+
+    fn end_transfer(&mut self) {
+        self.data = match &mut self.device {
+            Some(device) => device.exchange(self.data),
+            None => 0xFF,
+        };
+
+        self.control = self.control.unset_bit(TRANSFER_START_BIT);
+        self.transfer_cycles = 0;
+        self.bits_shifted = 0;
+        self.int_request |= 1 << SERIAL_INTERUPT;
+    }
+
+    pub fn readb(&self, addr: u16) -> u8 {
+        match addr {
+            SERIAL_DATA => self.data,
+            // Unused bits read as 1
+            SERIAL_CONTROL => self.control | 0b0111_1110,
+            _ => 0xFF, // Not a serial register
+        }
+    }
+
+    pub fn writeb(&mut self, addr: u16, value: u8) {
+        match addr {
+            SERIAL_DATA => self.data = value,
+            SERIAL_CONTROL => {
+                self.control = value & 0b1000_0001;
+                if self.is_transferring() {
+                    self.transfer_cycles = 0;
+                    self.bits_shifted = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// The attached device is not part of the state
+impl Stateful for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u32(self.transfer_cycles);
+        state.write_u8(self.bits_shifted);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()?;
+        self.transfer_cycles = state.read_u32()?;
+        self.bits_shifted = state.read_u8()?;
+        Ok(())
+    }
+}
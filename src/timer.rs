@@ -1,3 +1,7 @@
+use crate::savestate::StateError;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use crate::savestate::Stateful;
 use crate::utils::Bits;
 use crate::cpu::TIMER_INTERUPT;
 
@@ -14,103 +18,232 @@ pub const TMA: u16 = 0xFF06; // Timer modulator
 // Bit 2: Enabled/Disabled state
 pub const TMC: u16 = 0xFF07;
 
-// Interupts at each timer modulation
+// TIMA counts the falling edges of one bit of the system counter,
+// picked by TMC. The timer is the AND of that bit and the enable bit,
+// so resetting DIV or changing TMC can make it fall too.
 
-const CLOCK_SPEED: u32 = 4194304;
+// Cycles between an overflow of TIMA and its reload from TMA
+const RELOAD_DELAY: u8 = 4;
 
+#[derive(Default)]
 pub struct Timer {
     timer_controller: u8, // TMC
     timer: u8, // TIMA
     timer_modulo: u8, // TMA
-    timer_cycles: u32, // elasped cycles since last timer inc
-    timer_frequency: u32, // Cycles quantity to increment timer
-    divider_cycles: u32,
-    divider: u8, //DIV
+    counter: u16, // System counter, DIV is its upper byte
+    // TIMA reads 0 until the reload, writing it cancels the reload
+    reload_delay: u8,
+    // TIMA was just reloaded, writes to it are ignored and
+    // writes to TMA go through
+    reload_window: u8,
     pub int_request: u8,
 }
 
-impl Default for Timer {
-    fn default() -> Self {
-        Timer {
-            timer_controller: 0,
-            timer: 0,
-            timer_modulo: 0,
-            timer_cycles: 0,
-            timer_frequency: 256,
-            divider_cycles: 0,
-            divider: 0,
-            int_request: 0
-        }
-    }
-}
-
 impl Timer {
     pub fn update(&mut self, cycles: u32) {
         // cycles: how many CPU cycles have run
 
-        self.divider_cycles += cycles;
-        if self.divider_cycles > 255 {
-            self.divider = self.divider.wrapping_add((self.divider_cycles / 256) as u8);
-            self.divider_cycles %= 256;
+        for _ in 0..cycles {
+            self.reload_window = self.reload_window.saturating_sub(1);
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
+                if self.reload_delay == 0 {
+                    self.timer = self.timer_modulo;
+                    self.int_request |= 1 << TIMER_INTERUPT;
+                    self.reload_window = RELOAD_DELAY;
+                }
+            }
+
+            let signal = self.signal();
+            self.counter = self.counter.wrapping_add(1);
+            self.detect_falling_edge(signal);
         }
+    }
+
+    fn is_clock_enabled(&self) -> bool {
+        self.timer_controller.is_set(2)
+    }
 
-        if !self.is_clock_enabled() {
-            return
+    // Bit of the system counter clocking TIMA
+    fn selected_bit(&self) -> u8 {
+        match self.timer_controller & 0b11 {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7, // 16384 Hz
         }
+    }
 
-        self.timer_cycles += cycles;
-        while self.timer_cycles >= self.timer_frequency {
-            // Enough cycle have run to increase timer
-            if self.timer == 255 {
-                // About to overflow, set it to TMA
-                self.timer = self.timer_modulo;
+    fn signal(&self) -> bool {
+        self.is_clock_enabled() && self.counter.is_set(self.selected_bit())
+    }
 
-                self.int_request |= 1 << TIMER_INTERUPT;
-            }
-            else {
-                self.timer += 1;
-            }
-            self.timer_cycles -= self.timer_frequency;
+    fn detect_falling_edge(&mut self, previous_signal: bool) {
+        if previous_signal && !self.signal() {
+            self.increment();
         }
     }
 
-    fn is_clock_enabled(&self) -> bool {
-        self.timer_controller.is_set(2)
+    fn increment(&mut self) {
+        if self.timer == 255 {
+            // Reads 0 until reloaded from TMA
+            self.timer = 0;
+            self.reload_delay = RELOAD_DELAY;
+        } else {
+            self.timer += 1;
+        }
+    }
+
+    // Sets the internal counter DIV is the upper half of
+    pub fn set_divider_counter(&mut self, counter: u16) {
+        self.counter = counter;
     }
 
     pub fn readb(&self, addr: u16) -> u8 {
         match addr {
-            DIVIDER_REGISTER => self.divider,
+            DIVIDER_REGISTER => (self.counter >> 8) as u8,
             TIMA => self.timer,
             TMA => self.timer_modulo,
-            TMC => self.timer_controller,
-            _ => panic!("Forbidden address for timer {:#6X}.", addr)
+            TMC => self.timer_controller | 0xF8, // Unused bits read 1
+            _ => 0xFF, // Not a timer register
         }
     }
 
     pub fn writeb(&mut self, addr: u16, value: u8) {
+        let signal = self.signal();
         match addr {
-            DIVIDER_REGISTER => self.divider = 0,// Forbidden so we reset divider
-            TMC => self.set_clock_freq(value),
-            TIMA => self.timer = value,
-            TMA => self.timer_modulo = value,
-            _ => panic!("Forbidden address for timer {:#6X}.", addr),
+            DIVIDER_REGISTER => self.counter = 0, // Any write resets the whole counter
+            TMC => self.timer_controller = value & 0x07,
+            // Ignored right after a reload
+            TIMA if self.reload_window == 0 => {
+                self.timer = value;
+                self.reload_delay = 0;
+            }
+            TMA => {
+                self.timer_modulo = value;
+                if self.reload_window > 0 {
+                    self.timer = value;
+                }
+            }
+            _ => (),
         };
+        self.detect_falling_edge(signal);
     }
+}
 
-    fn set_clock_freq(&mut self, value: u8) {
-        // On writing on TMC
-        self.timer_controller = value;
-        self.timer_frequency = CLOCK_SPEED / self.get_clock_freq();
+impl Stateful for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.timer_controller);
+        state.write_u8(self.timer);
+        state.write_u8(self.timer_modulo);
+        state.write_u16(self.counter);
+        state.write_u8(self.reload_delay);
+        state.write_u8(self.reload_window);
     }
 
-    fn get_clock_freq(&self) -> u32 {
-        match self.timer_controller & 0b11 {
-            0b00 => 4096,
-            0b01 => 262144,
-            0b10 => 65536,
-            0b11 => 16384,
-            _ => panic!("Should not happend!")
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.timer_controller = state.read_u8()? & 0x07;
+        self.timer = state.read_u8()?;
+        self.timer_modulo = state.read_u8()?;
+        self.reload_delay = 0;
+        self.reload_window = 0;
+        if state.version() >= 8 {
+            self.counter = state.read_u16()?;
+            self.reload_delay = state.read_u8()?;
+            self.reload_window = state.read_u8()?;
+        } else {
+            // Separate DIV and TIMA counters before version 8,
+            // the TIMA one is lost
+            let _timer_cycles = state.read_u32()?;
+            let divider_cycles = state.read_u32()?;
+            let divider = state.read_u8()?;
+            self.counter = (divider as u16) << 8 | (divider_cycles & 0xFC) as u16;
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Running at 262144 Hz, bit 3 falls every 16 cycles
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::default();
+        timer.writeb(TMC, 0x05);
+        timer
+    }
+
+    #[test]
+    fn counts_falling_edges() {
+        let mut timer = fast_timer();
+        timer.update(15);
+        assert_eq!(timer.readb(TIMA), 0);
+        timer.update(1);
+        assert_eq!(timer.readb(TIMA), 1);
+        timer.update(16 * 10);
+        assert_eq!(timer.readb(TIMA), 11);
+        assert_eq!(timer.readb(DIVIDER_REGISTER), 0);
+        timer.update(80);
+        assert_eq!(timer.readb(DIVIDER_REGISTER), 1);
+    }
+
+    #[test]
+    fn resetting_div_or_tmc_can_increment() {
+        let mut timer = fast_timer();
+        timer.update(8);
+        timer.writeb(DIVIDER_REGISTER, 0x12);
+        assert_eq!(timer.readb(TIMA), 1);
+        assert_eq!(timer.readb(DIVIDER_REGISTER), 0);
+
+        timer.update(8);
+        timer.writeb(TMC, 0x04);
+        assert_eq!(timer.readb(TIMA), 2);
+        assert_eq!(timer.readb(TMC), 0xFC);
+    }
+
+    #[test]
+    fn overflow_reloads_after_delay() {
+        let mut timer = fast_timer();
+        timer.writeb(TMA, 0x42);
+        timer.writeb(TIMA, 0xFF);
+        timer.update(16);
+        assert_eq!(timer.readb(TIMA), 0);
+        assert_eq!(timer.int_request, 0);
+
+        timer.update(4);
+        assert_eq!(timer.readb(TIMA), 0x42);
+        assert_eq!(timer.int_request, 1 << TIMER_INTERUPT);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn writing_tima_cancels_reload() {
+        let mut timer = fast_timer();
+        timer.writeb(TMA, 0x42);
+        timer.writeb(TIMA, 0xFF);
+        timer.update(16);
+        timer.writeb(TIMA, 0x10);
+
+        timer.update(4);
+        assert_eq!(timer.readb(TIMA), 0x10);
+        assert_eq!(timer.int_request, 0);
+    }
+
+    #[test]
+    fn writes_right_after_reload() {
+        let mut timer = fast_timer();
+        timer.writeb(TMA, 0x42);
+        timer.writeb(TIMA, 0xFF);
+        timer.update(20);
+
+        // TIMA ignores the write, TMA goes through to it
+        timer.writeb(TIMA, 0x10);
+        assert_eq!(timer.readb(TIMA), 0x42);
+        timer.writeb(TMA, 0x24);
+        assert_eq!(timer.readb(TIMA), 0x24);
+
+        timer.update(4);
+        timer.writeb(TIMA, 0x10);
+        assert_eq!(timer.readb(TIMA), 0x10);
+    }
+}
@@ -9,6 +9,13 @@ pub fn to_u16(msb: u8, lsb: u8) -> u16 {
     ((msb as u16) << 8) | lsb as u16
 }
 
+// FNV-1a, a simple hash that is stable across runs and platforms
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
 pub trait Bits {
     // Returns true if bit at index is set
     fn is_set(&self, index: u8) -> bool;
@@ -56,6 +63,7 @@ impl Bits for u16 {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use crate::utils::Bits;
 
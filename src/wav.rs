@@ -0,0 +1,142 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// Streams 16-bit stereo PCM to a .wav file. The sizes in the header are
+// patched when finished, so the file plays back even if recording stops early.
+pub struct WavWriter<W: Write + Seek> {
+    writer: Option<W>,
+    data_size: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, sample_rate: u32) -> std::io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> std::io::Result<Self> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer: Some(writer),
+            data_size: 0,
+        })
+    }
+
+    // Interleaved left and right samples between -1 and 1, as produced
+    // by GameBoy::take_samples
+    pub fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        // The RIFF size, header included, must fit in 32 bits
+        let data_size = u32::try_from(samples.len() * 2)
+            .ok()
+            .and_then(|size| self.data_size.checked_add(size))
+            .filter(|size| size.checked_add(HEADER_SIZE - 8).is_some())
+            .ok_or_else(|| std::io::Error::other("WAV file size limit reached"))?;
+
+        let writer = self.writer.as_mut().expect("Writer already finished");
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size = data_size;
+        Ok(())
+    }
+
+    // Number of stereo frames written so far
+    pub fn frames(&self) -> u32 {
+        self.data_size / 4
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.patch_header()?;
+        Ok(self.writer.take().unwrap())
+    }
+
+    fn patch_header(&mut self) -> std::io::Result<()> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.seek(SeekFrom::Start(4))?;
+        writer.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        writer.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        writer.write_all(&self.data_size.to_le_bytes())?;
+        writer.seek(SeekFrom::End(0))?;
+        writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.patch_header();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_holds_sizes() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        assert_eq!(wav.frames(), 2);
+        let data = wav.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 48000);
+        assert_eq!(
+            u32::from_le_bytes(data[28..32].try_into().unwrap()),
+            48000 * 4
+        );
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+    }
+
+    #[test]
+    fn samples_are_clamped() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write_samples(&[1.0, -1.0, 3.0, -3.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, vec![32767, -32767, 32767, -32767]);
+    }
+
+    #[test]
+    fn stops_at_size_limit() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.data_size = u32::MAX - 36 - 4;
+        wav.write_samples(&[0.0, 0.0]).unwrap();
+        assert!(wav.write_samples(&[0.0, 0.0]).is_err());
+        assert_eq!(wav.data_size, u32::MAX - 36);
+    }
+}
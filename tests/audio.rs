@@ -0,0 +1,60 @@
+mod common;
+
+use gb_rs::wav::WavWriter;
+use gb_rs::GameBoy;
+
+use common::rom_with_code;
+
+// Plays a square wave on channel 2
+fn tone_rom() -> Vec<u8> {
+    rom_with_code(&[
+        0x3e, 0xf0, 0xe0, 0x17, // NR22: volume 15
+        0x3e, 0x80, 0xe0, 0x16, // NR21: 50% duty
+        0x3e, 0x00, 0xe0, 0x18, // NR23
+        0x3e, 0x87, 0xe0, 0x19, // NR24: trigger
+        0x18, 0xfe, // JR -2
+    ])
+}
+
+#[test]
+fn channel_2_is_audible() {
+    let mut gameboy = GameBoy::from_rom_bytes(tone_rom()).unwrap();
+    gameboy.set_sample_rate(48000);
+    for _ in 0..10 {
+        gameboy.run_frame().unwrap();
+    }
+
+    let samples = gameboy.take_samples();
+    // 10 frames at ~59.7 Hz, stereo
+    assert!((15900..16200).contains(&samples.len()), "{}", samples.len());
+    let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+    let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+    assert!(max - min > 0.5, "{} {}", min, max);
+}
+
+#[test]
+fn no_samples_when_disabled() {
+    let mut gameboy = GameBoy::from_rom_bytes(tone_rom()).unwrap();
+    gameboy.set_sample_rate(0);
+    gameboy.run_frame().unwrap();
+    assert!(gameboy.take_samples().is_empty());
+}
+
+#[test]
+fn recording_to_wav() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("tone.wav");
+    let mut gameboy = GameBoy::from_rom_bytes(tone_rom()).unwrap();
+    let mut wav = WavWriter::create(&path, gameboy.sample_rate()).unwrap();
+    for _ in 0..10 {
+        gameboy.run_frame().unwrap();
+        wav.write_samples(&gameboy.take_samples()).unwrap();
+    }
+    let frames = wav.frames();
+    wav.finish().unwrap();
+
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data.len(), 44 + frames as usize * 4);
+    assert_eq!(&data[8..16], b"WAVEfmt ");
+    // 10 frames at ~59.7 Hz
+    assert!((7300..7450).contains(&frames), "{}", frames);
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use std::path::Path;
+
+use gb_rs::battery::BatterySave;
+use gb_rs::GameBoy;
+
+use common::rom_with_code;
+
+// MBC3+RAM+BATTERY cartridge writing `value` at the start of its RAM
+fn saving_rom(cartridge_type: u8, value: u8) -> Vec<u8> {
+    let mut rom = rom_with_code(&[
+        0x3e, 0x0a, // LD A, 0x0A
+        0xea, 0x00, 0x00, // LD (0x0000), A ; enable RAM
+        0x3e, value, // LD A, value
+        0xea, 0x00, 0xa0, // LD (0xA000), A
+        0x18, 0xfe, // JR -2
+    ]);
+    rom[0x147] = cartridge_type;
+    rom[0x149] = 0x03; // 32KB of RAM
+    rom
+}
+
+fn rom_path(name: &str) -> std::path::PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_file(path.with_extension("sav"));
+    path
+}
+
+#[test]
+fn battery_ram_survives_restart() {
+    let path = rom_path("battery.gb");
+
+    let mut gameboy = GameBoy::from_rom_bytes(saving_rom(0x13, 0x42)).unwrap();
+    let mut battery = BatterySave::for_rom(&path);
+    battery.load(&mut gameboy).unwrap();
+    gameboy.run_frame().unwrap();
+    battery.flush(&mut gameboy).unwrap();
+
+    let saved = std::fs::read(path.with_extension("sav")).unwrap();
+    assert_eq!(saved.len(), 0x8000);
+    assert_eq!(saved[0], 0x42);
+
+    // Same cartridge, but the program does not write anything
+    let mut rom = saving_rom(0x13, 0x42);
+    rom[0x150..0x15a].fill(0);
+    let mut gameboy = GameBoy::from_rom_bytes(rom).unwrap();
+    let mut battery = BatterySave::for_rom(&path);
+    battery.load(&mut gameboy).unwrap();
+    // RAM is disabled until the game enables it
+    assert_eq!(gameboy.peek(0xA000), 0xFF);
+    assert_eq!(gameboy.battery_ram().unwrap()[0], 0x42);
+}
+
+#[test]
+fn no_save_without_battery() {
+    let path = rom_path("no-battery.gb");
+
+    // MBC3+RAM, no battery
+    let mut gameboy = GameBoy::from_rom_bytes(saving_rom(0x12, 0x42)).unwrap();
+    assert!(gameboy.battery_ram().is_none());
+
+    let mut battery = BatterySave::for_rom(&path);
+    battery.load(&mut gameboy).unwrap();
+    gameboy.run_frame().unwrap();
+    battery.flush(&mut gameboy).unwrap();
+    assert!(!path.with_extension("sav").exists());
+}
+
+#[test]
+fn rtc_is_appended_to_save() {
+    let path = rom_path("rtc.gb");
+
+    // MBC3+TIMER+RAM+BATTERY
+    let mut gameboy = GameBoy::from_rom_bytes(saving_rom(0x10, 0x42)).unwrap();
+    let mut battery = BatterySave::for_rom(&path);
+    battery.load(&mut gameboy).unwrap();
+    gameboy.run_frame().unwrap();
+    battery.save(&mut gameboy).unwrap();
+
+    let saved = std::fs::read(path.with_extension("sav")).unwrap();
+    assert_eq!(saved.len(), 0x8000 + 48);
+    assert_eq!(saved[0], 0x42);
+}
@@ -0,0 +1,41 @@
+use gb_rs::cartridge::Cartridge;
+use gb_rs::EmuError;
+use gb_rs::GameBoy;
+
+fn cartridge() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x00] = 0x42;
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]); // JR -2
+    Cartridge::from_bytes(rom).unwrap()
+}
+
+// Runs NOPs up to 0xFC, then unmaps itself and falls into the cartridge at 0x100
+fn boot_rom() -> Vec<u8> {
+    let mut boot = vec![0; 0x100];
+    boot[0xfc..0x100].copy_from_slice(&[
+        0x3e, 0x01, // LD A, 1
+        0xe0, 0x50, // LDH (0x50), A
+    ]);
+    boot
+}
+
+#[test]
+fn boot_rom_runs_then_unmaps() {
+    let mut gameboy = GameBoy::with_boot_rom(cartridge(), boot_rom()).unwrap();
+    assert_eq!(gameboy.registers().pc, 0);
+    assert_eq!(gameboy.registers().sp, 0);
+    assert_eq!(gameboy.peek(0x0000), 0x00);
+
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.registers().pc, 0x100);
+    assert_eq!(gameboy.registers().a, 0x01);
+    assert_eq!(gameboy.peek(0x0000), 0x42);
+}
+
+#[test]
+fn boot_rom_must_be_256_bytes() {
+    assert!(matches!(
+        GameBoy::with_boot_rom(cartridge(), vec![0; 0x900]),
+        Err(EmuError::InvalidBootRom(0x900))
+    ));
+}
@@ -0,0 +1,130 @@
+// Runs blargg and Mooneye test ROMs found in the test ROM directory
+// and reports a result for each of them.
+//
+// blargg ROMs print "Passed" or "Failed" through the serial port.
+// Mooneye ROMs execute LD B,B once done and leave the Fibonacci
+// sequence in B, C, D, E, H and L on success, or 0x42 on failure.
+
+mod common;
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use gb_rs::registers::Registers;
+use gb_rs::GameBoy;
+use gb_rs::SerialDevice;
+
+const LD_B_B: u8 = 0x40;
+const CLOCK_SPEED: u64 = 4194304;
+
+// Longest blargg ROM (cpu_instrs) takes about a minute of emulated time
+const TIMEOUT_SECONDS: u64 = 120;
+
+#[derive(PartialEq)]
+enum Outcome {
+    Passed,
+    Failed(String),
+    Timeout,
+}
+
+struct SerialCapture(Rc<RefCell<Vec<u8>>>);
+
+impl SerialDevice for SerialCapture {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.0.borrow_mut().push(byte);
+        0xFF
+    }
+}
+
+fn mooneye_outcome(reg: &Registers) -> Outcome {
+    let values = [reg.b, reg.c, reg.d, reg.e, reg.h, reg.l];
+    if values == [3, 5, 8, 13, 21, 34] {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(format!("registers {:02x?}", values))
+    }
+}
+
+fn blargg_outcome(output: &[u8]) -> Option<Outcome> {
+    let text = String::from_utf8_lossy(output);
+    if text.contains("Passed") {
+        Some(Outcome::Passed)
+    } else if text.contains("Failed") {
+        let summary = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        Some(Outcome::Failed(summary))
+    } else {
+        None
+    }
+}
+
+fn run_rom(path: &Path) -> Outcome {
+    let rom = match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut gameboy = match GameBoy::from_rom_bytes(rom) {
+        Ok(gameboy) => gameboy,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    gameboy.set_serial_device(Box::new(SerialCapture(output.clone())));
+
+    let mut cycles: u64 = 0;
+    let mut output_len = 0;
+    while cycles < TIMEOUT_SECONDS * CLOCK_SPEED {
+        let breakpoint = gameboy.peek(gameboy.registers().pc) == LD_B_B;
+        cycles += match gameboy.step_instruction() {
+            Ok(cycles) => cycles as u64,
+            Err(e) => return Outcome::Failed(e.to_string()),
+        };
+
+        if breakpoint {
+            return mooneye_outcome(gameboy.registers());
+        }
+
+        let output = output.borrow();
+        if output.len() != output_len {
+            output_len = output.len();
+            if let Some(outcome) = blargg_outcome(&output) {
+                return outcome;
+            }
+        }
+    }
+
+    Outcome::Timeout
+}
+
+#[test]
+fn test_roms() {
+    let dir = common::rom_dir();
+    let roms: Vec<PathBuf> = common::find_roms(&dir);
+    if roms.is_empty() {
+        eprintln!("No test ROM found in {}, skipping", dir.display());
+        return;
+    }
+
+    let outcomes = common::run_parallel(&roms, |rom| run_rom(rom));
+
+    let mut report = String::new();
+    let mut failures = 0;
+    for (rom, outcome) in roms.iter().zip(outcomes.iter()) {
+        let name = rom.strip_prefix(&dir).unwrap_or(rom).display();
+        let result = match outcome {
+            Outcome::Passed => "PASS".to_string(),
+            Outcome::Failed(reason) => format!("FAIL  {}", reason),
+            Outcome::Timeout => "TIMEOUT".to_string(),
+        };
+        if *outcome != Outcome::Passed {
+            failures += 1;
+        }
+        writeln!(report, "{:<60} {}", name, result).unwrap();
+    }
+    writeln!(report, "{}/{} passed", roms.len() - failures, roms.len()).unwrap();
+
+    println!("{}", report);
+    assert_eq!(failures, 0, "{} test ROM(s) did not pass", failures);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use gb_rs::EmuError;
+use gb_rs::GameBoy;
+use gb_rs::Lockup;
+
+use common::rom_with_code;
+
+#[test]
+fn illegal_opcode_locks_up_cpu() {
+    let mut gameboy = GameBoy::from_rom_bytes(rom_with_code(&[0x00, 0xd3])).unwrap();
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.lockup(), Some(Lockup { pc: 0x151, opcode: 0xd3 }));
+    assert_eq!(gameboy.registers().pc, 0x151);
+
+    // The CPU is stuck but the timer keeps counting
+    let divider = gameboy.peek(0xff04);
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.registers().pc, 0x151);
+    assert_ne!(gameboy.peek(0xff04), divider);
+}
+
+#[test]
+fn lockup_breaks_when_asked() {
+    let mut gameboy = GameBoy::from_rom_bytes(rom_with_code(&[0x00, 0xfd])).unwrap();
+    gameboy.set_break_on_lockup(true);
+    match gameboy.run_frame() {
+        Err(EmuError::CpuLockup { pc, opcode }) => assert_eq!((pc, opcode), (0x151, 0xfd)),
+        other => panic!("Expected a lock-up, got {:?}", other),
+    }
+
+    // Resuming runs the hung machine
+    assert!(gameboy.run_frame().is_ok());
+}
+
+#[test]
+fn bad_roms_are_rejected() {
+    assert!(matches!(
+        GameBoy::from_rom_bytes(vec![0; 0x100]),
+        Err(EmuError::InvalidHeader(_))
+    ));
+
+    let mut unknown = rom_with_code(&[]);
+    unknown[0x147] = 0x42;
+    assert!(matches!(
+        GameBoy::from_rom_bytes(unknown),
+        Err(EmuError::UnknownCartridgeType(0x42))
+    ));
+
+    // HuC1
+    let mut unsupported = rom_with_code(&[]);
+    unsupported[0x147] = 0xFF;
+    assert!(matches!(
+        GameBoy::from_rom_bytes(unsupported),
+        Err(EmuError::UnsupportedCartridge(_))
+    ));
+}
@@ -0,0 +1,110 @@
+use gb_rs::gbs::GbsError;
+use gb_rs::gbs::GbsPlayer;
+use gb_rs::EmuError;
+
+// Init stores the song at 0xC001 and starts a tone on channel 2,
+// play counts its calls at 0xC000
+fn gbs(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+    let mut data = vec![0; 0x70];
+    data[0..4].copy_from_slice(b"GBS\x01");
+    data[4] = 3; // Songs
+    data[5] = 1; // First song
+    data[0x06..0x08].copy_from_slice(&0x400u16.to_le_bytes()); // Load
+    data[0x08..0x0A].copy_from_slice(&0x400u16.to_le_bytes()); // Init
+    data[0x0A..0x0C].copy_from_slice(&0x420u16.to_le_bytes()); // Play
+    data[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes()); // Stack
+    data[0x0E] = timer_modulo;
+    data[0x0F] = timer_control;
+
+    let mut code = vec![0; 0x30];
+    code[0x00..0x14].copy_from_slice(&[
+        0xea, 0x01, 0xc0, // LD (0xC001), A
+        0x3e, 0xf0, 0xe0, 0x17, // NR22: volume 15
+        0x3e, 0x80, 0xe0, 0x16, // NR21: 50% duty
+        0x3e, 0x00, 0xe0, 0x18, // NR23
+        0x3e, 0x87, 0xe0, 0x19, // NR24: trigger
+        0xc9, // RET
+    ]);
+    code[0x20..0x25].copy_from_slice(&[
+        0x21, 0x00, 0xc0, // LD HL, 0xC000
+        0x34, // INC (HL)
+        0xc9, // RET
+    ]);
+    data.extend(code);
+    data
+}
+
+#[test]
+fn play_runs_on_v_blank() {
+    let mut player = GbsPlayer::new(&gbs(0, 0)).unwrap();
+    player.set_sample_rate(48000);
+    for _ in 0..10 {
+        player.run_frame().unwrap();
+    }
+
+    assert_eq!(player.gameboy().peek(0xC001), 0);
+    let calls = player.gameboy().peek(0xC000);
+    assert!((9..=10).contains(&calls), "{}", calls);
+
+    let samples = player.take_samples();
+    let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+    let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+    assert!(max - min > 0.5, "{} {}", min, max);
+}
+
+#[test]
+fn play_runs_on_timer() {
+    // 4096 Hz, overflowing every 64 ticks once TIMA reached the modulo
+    // after its first 256 ticks: 61 calls in a second
+    let mut player = GbsPlayer::new(&gbs(0xC0, 0x04)).unwrap();
+    for _ in 0..60 {
+        player.run_frame().unwrap();
+    }
+
+    let calls = player.gameboy().peek(0xC000);
+    assert!((60..=62).contains(&calls), "{}", calls);
+}
+
+#[test]
+fn songs_can_be_selected() {
+    let mut player = GbsPlayer::new(&gbs(0, 0)).unwrap();
+    assert_eq!(player.song(), 0);
+
+    player.start_song(2).unwrap();
+    player.run_frame().unwrap();
+    assert_eq!(player.song(), 2);
+    assert_eq!(player.gameboy().peek(0xC001), 2);
+
+    match player.start_song(3) {
+        Err(EmuError::InvalidGbs(GbsError::InvalidSong { song: 3, count: 3 })) => (),
+        result => panic!("{:?}", result.err()),
+    }
+}
+
+#[test]
+fn play_waits_for_init() {
+    // Init spins for about 3 frames, then stores the play calls it saw
+    // at 0xC002 and marks 0xC003
+    let mut data = gbs(0, 0);
+    data[0x70..0x84].copy_from_slice(&[
+        0x01, 0x00, 0x20, // LD BC, 0x2000
+        0x0b, // DEC BC
+        0x78, // LD A, B
+        0xb1, // OR C
+        0x20, 0xfb, // JR NZ, -5
+        0xfa, 0x00, 0xc0, // LD A, (0xC000)
+        0xea, 0x02, 0xc0, // LD (0xC002), A
+        0x3e, 0x01, // LD A, 1
+        0xea, 0x03, 0xc0, // LD (0xC003), A
+        0xc9, // RET
+    ]);
+
+    let mut player = GbsPlayer::new(&data).unwrap();
+    for _ in 0..6 {
+        player.run_frame().unwrap();
+    }
+
+    assert_eq!(player.gameboy().peek(0xC003), 1);
+    assert_eq!(player.gameboy().peek(0xC002), 0);
+    assert_ne!(player.gameboy().peek(0xC000), 0);
+}
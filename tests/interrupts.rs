@@ -0,0 +1,179 @@
+mod common;
+
+use gb_rs::GameBoy;
+use gb_rs::JoypadInput;
+
+use common::rom_with_code;
+
+// Work RAM starts random, the code clears it first. The V-Blank handler
+// stores 0xFF at 0xC002, the timer handler at 0xC001 and a jump to
+// 0x0000 stores 0x99 at 0xC003.
+fn rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = rom_with_code(code);
+    rom[0x00..0x07].copy_from_slice(&[0x3e, 0x99, 0xea, 0x03, 0xc0, 0x18, 0xfe]); // LD A, 0x99; LD (0xC003), A; JR -2
+    rom[0x40..0x46].copy_from_slice(&[0x3e, 0xff, 0xea, 0x02, 0xc0, 0xd9]); // LD A, 0xFF; LD (0xC002), A; RETI
+    rom[0x50..0x56].copy_from_slice(&[0x3e, 0xff, 0xea, 0x01, 0xc0, 0xd9]); // LD A, 0xFF; LD (0xC001), A; RETI
+    rom
+}
+
+// Runs the code with interupts disabled and results cleared,
+// B is counted up by the code and stored at 0xC000 when done
+fn run(code: &[u8]) -> GameBoy {
+    let mut program = vec![
+        0xf3, // DI
+        0xaf, // XOR A
+        0xea, 0x00, 0xc0, // LD (0xC000), A
+        0xea, 0x01, 0xc0, // LD (0xC001), A
+        0xea, 0x02, 0xc0, // LD (0xC002), A
+        0xea, 0x03, 0xc0, // LD (0xC003), A
+        0x06, 0x00, // LD B, 0
+    ];
+    program.extend(code);
+    program.extend([
+        0x78, // LD A, B
+        0xea, 0x00, 0xc0, // LD (0xC000), A
+        0x18, 0xfe, // JR -2
+    ]);
+
+    let mut gameboy = GameBoy::from_rom_bytes(rom(&program)).unwrap();
+    gameboy.run_frame().unwrap();
+    gameboy
+}
+
+// Runs the code after requesting and enabling the timer interupt
+fn run_pending_timer(code: &[u8]) -> GameBoy {
+    let mut program = vec![
+        0x3e, 0x04, // LD A, 0x04
+        0xe0, 0xff, // LDH (IE), A
+        0xe0, 0x0f, // LDH (IF), A
+    ];
+    program.extend(code);
+    run(&program)
+}
+
+#[test]
+fn ei_takes_effect_after_next_instruction() {
+    let gameboy = run_pending_timer(&[0xfb, 0x04, 0x04]); // EI; INC B; INC B
+    assert_eq!(gameboy.peek(0xc001), 0xff);
+    assert_eq!(gameboy.peek(0xc000), 2);
+
+    // DI right after EI cancels it
+    let gameboy = run_pending_timer(&[0xfb, 0xf3, 0x04]); // EI; DI; INC B
+    assert_eq!(gameboy.peek(0xc001), 0);
+    assert_eq!(gameboy.peek(0xc000), 1);
+}
+
+#[test]
+fn halt_bug_repeats_next_byte() {
+    let gameboy = run_pending_timer(&[0x76, 0x04]); // HALT; INC B
+    assert_eq!(gameboy.peek(0xc001), 0);
+    assert_eq!(gameboy.peek(0xc000), 2);
+}
+
+#[test]
+fn halt_wakes_up_without_ime() {
+    let gameboy = run(&[
+        0x3e, 0x04, // LD A, 0x04
+        0xe0, 0xff, // LDH (IE), A
+        0x3e, 0x05, // LD A, 0x05
+        0xe0, 0x07, // LDH (TAC), A: 262144 Hz
+        0x76, // HALT
+        0x04, // INC B
+    ]);
+    assert_eq!(gameboy.peek(0xc000), 1);
+    assert_eq!(gameboy.peek(0xc001), 0);
+    assert_eq!(gameboy.peek(0xff0f) & 0x04, 0x04);
+}
+
+#[test]
+fn stop_waits_for_joypad() {
+    let mut gameboy = run(&[
+        0x3e, 0x20, // LD A, 0x20
+        0xe0, 0x00, // LDH (P1), A: select directions
+        0x10, 0x00, // STOP
+        0x04, // INC B
+    ]);
+    assert_eq!(gameboy.peek(0xc000), 0);
+    assert_eq!(gameboy.peek(0xff04), 0);
+
+    // DIV is halted
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.peek(0xc000), 0);
+    assert_eq!(gameboy.peek(0xff04), 0);
+
+    gameboy.set_button(JoypadInput::Right, true);
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.peek(0xc000), 1);
+    assert_ne!(gameboy.peek(0xff04), 0);
+    assert_eq!(gameboy.peek(0xff0f) & 0x10, 0x10);
+}
+
+#[test]
+fn one_interupt_is_dispatched_at_a_time() {
+    let gameboy = run(&[
+        0x3e, 0x05, // LD A, 0x05
+        0xe0, 0xff, // LDH (IE), A
+        0xe0, 0x0f, // LDH (IF), A: V-Blank and timer
+        0xfb, // EI
+        0x00, // NOP: V-Blank is dispatched first
+        0xf3, // DI: in the V-Blank handler, RETI enables the timer
+        0xf0, 0x0f, // LDH A, (IF)
+        0x47, // LD B, A
+    ]);
+    assert_eq!(gameboy.peek(0xc002), 0xff);
+    assert_eq!(gameboy.peek(0xc001), 0xff);
+    // The timer ran after RETI, before DI
+    assert_eq!(gameboy.peek(0xc000), 0xe0);
+}
+
+#[test]
+fn dispatch_takes_five_m_cycles() {
+    let program = [
+        0xf3, // DI
+        0x3e, 0x04, // LD A, 0x04
+        0xe0, 0xff, // LDH (IE), A
+        0xe0, 0x0f, // LDH (IF), A
+        0xfb, // EI
+        0x00, // NOP
+    ];
+    let mut gameboy = GameBoy::from_rom_bytes(rom(&program)).unwrap();
+    while gameboy.registers().pc != 0x150 + 8 {
+        gameboy.step_instruction().unwrap();
+    }
+
+    assert_eq!(gameboy.step_instruction().unwrap(), 4 + 20);
+    assert_eq!(gameboy.registers().pc, 0x50);
+}
+
+#[test]
+fn pushing_over_ie_changes_the_dispatch() {
+    // The upper byte of PC, 0x01, lands in IE: the timer is no longer
+    // enabled and V-Blank is serviced instead
+    let gameboy = run(&[
+        0x31, 0x00, 0x00, // LD SP, 0x0000
+        0x3e, 0x04, // LD A, 0x04
+        0xe0, 0xff, // LDH (IE), A
+        0x3e, 0x05, // LD A, 0x05
+        0xe0, 0x0f, // LDH (IF), A
+        0xfb, // EI
+        0x00, // NOP
+        0xf3, // DI
+    ]);
+    assert_eq!(gameboy.peek(0xc002), 0xff);
+    assert_eq!(gameboy.peek(0xc001), 0);
+    assert_eq!(gameboy.peek(0xffff), 0x01);
+
+    // With nothing left to service, the CPU jumps to 0x0000
+    let gameboy = run(&[
+        0x31, 0x00, 0x00, // LD SP, 0x0000
+        0x3e, 0x04, // LD A, 0x04
+        0xe0, 0xff, // LDH (IE), A
+        0xe0, 0x0f, // LDH (IF), A
+        0xfb, // EI
+        0x00, // NOP
+    ]);
+    assert_eq!(gameboy.peek(0xc003), 0x99);
+    assert_eq!(gameboy.peek(0xc001), 0);
+    // Still requested, next to the V-Blank of the frame
+    assert_eq!(gameboy.peek(0xff0f), 0xe5);
+}
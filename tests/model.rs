@@ -0,0 +1,71 @@
+use gb_rs::cartridge::Cartridge;
+use gb_rs::GameBoy;
+use gb_rs::Model;
+
+fn cartridge(cgb_flag: u8) -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x104] = 0xCE; // First logo byte
+    rom[0x143] = cgb_flag;
+    rom[0x14D] = 0x42; // Header checksum
+    Cartridge::from_bytes(rom).unwrap()
+}
+
+fn ab(model: Model, cgb_flag: u8) -> (u8, u8) {
+    let gameboy = GameBoy::with_model(cartridge(cgb_flag), model);
+    (gameboy.registers().a, gameboy.registers().b)
+}
+
+#[test]
+fn models_are_told_apart_by_a_and_b() {
+    assert_eq!(ab(Model::Dmg0, 0), (0x01, 0xFF));
+    assert_eq!(ab(Model::Dmg, 0), (0x01, 0x00));
+    assert_eq!(ab(Model::Mgb, 0), (0xFF, 0x00));
+    assert_eq!(ab(Model::Sgb, 0), (0x01, 0x00));
+    assert_eq!(ab(Model::Sgb2, 0), (0xFF, 0x00));
+    assert_eq!(ab(Model::Cgb, 0x80), (0x11, 0x00));
+    assert_eq!(ab(Model::Agb, 0x80), (0x11, 0x01));
+}
+
+#[test]
+fn dmg_post_boot_state() {
+    let gameboy = GameBoy::with_model(cartridge(0), Model::Dmg);
+    let registers = gameboy.registers();
+    assert_eq!((registers.pc, registers.sp, registers.f), (0x100, 0xFFFE, 0xB0));
+    assert_eq!(gameboy.peek(0xFF04), 0xAB);
+
+    // The logo is on screen
+    assert_eq!(gameboy.peek(0xFF40), 0x91);
+    assert_eq!(gameboy.peek(0xFF47), 0xFC);
+    assert_eq!(gameboy.peek(0xFF0F), 0xE1);
+
+    // 0xCE = 1100 1110, the upper nibble doubled
+    assert_eq!(gameboy.peek(0x8010), 0xF0);
+    assert_eq!(gameboy.peek(0x9904), 1);
+    assert_eq!(gameboy.peek(0x9910), 25);
+}
+
+#[test]
+fn interupts_start_disabled() {
+    // V-Blank is requested when the game starts, but the boot ROM hands
+    // over with IME off, as without the post-boot state
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x105].copy_from_slice(&[
+        0x3E, 0x01, // LD A, 0x01
+        0xE0, 0xFF, // LDH (IE), A
+        0x00, // NOP
+    ]);
+    let mut gameboy = GameBoy::with_model(Cartridge::from_bytes(rom).unwrap(), Model::Dmg);
+    for _ in 0..3 {
+        gameboy.step_instruction().unwrap();
+    }
+
+    assert_eq!(gameboy.registers().pc, 0x105);
+    assert_eq!(gameboy.peek(0xFF0F), 0xE1);
+}
+
+#[test]
+fn model_names() {
+    assert_eq!("sgb2".parse(), Ok(Model::Sgb2));
+    assert_eq!("CGB".parse(), Ok(Model::Cgb));
+    assert!("gba".parse::<Model>().is_err());
+}
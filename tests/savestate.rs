@@ -0,0 +1,60 @@
+mod common;
+
+use gb_rs::GameBoy;
+use gb_rs::StateError;
+
+use common::rom_with_code;
+
+// Increments A and writes it to work RAM in an endless loop
+fn counter_rom(marker: u8) -> Vec<u8> {
+    rom_with_code(&[
+        0x3c, // INC A
+        0xea, 0x00, 0xc0, // LD (0xC000), A
+        0x18, 0xfa, // JR -6
+        0x00, marker,
+    ])
+}
+
+#[test]
+fn load_restores_saved_machine() {
+    let mut gameboy = GameBoy::from_rom_bytes(counter_rom(0)).unwrap();
+    gameboy.run_frame().unwrap();
+    let state = gameboy.save_state();
+    let pc = gameboy.registers().pc;
+    let a = gameboy.registers().a;
+    let counter = gameboy.peek(0xC000);
+
+    gameboy.run_frame().unwrap();
+    assert_ne!(gameboy.peek(0xC000), counter);
+
+    assert_eq!(gameboy.load_state(&state), Ok(()));
+    assert_eq!(gameboy.registers().pc, pc);
+    assert_eq!(gameboy.registers().a, a);
+    assert_eq!(gameboy.peek(0xC000), counter);
+    assert_eq!(gameboy.save_state(), state);
+}
+
+#[test]
+fn load_rejects_other_rom() {
+    let mut gameboy = GameBoy::from_rom_bytes(counter_rom(0)).unwrap();
+    gameboy.run_frame().unwrap();
+    let state = gameboy.save_state();
+
+    let mut other = GameBoy::from_rom_bytes(counter_rom(1)).unwrap();
+    other.run_frame().unwrap();
+    let before = other.save_state();
+
+    assert_eq!(other.load_state(&state), Err(StateError::WrongRom));
+    assert_eq!(other.save_state(), before);
+}
+
+#[test]
+fn failed_load_leaves_machine_untouched() {
+    let mut gameboy = GameBoy::from_rom_bytes(counter_rom(0)).unwrap();
+    gameboy.run_frame().unwrap();
+    let before = gameboy.save_state();
+
+    assert_eq!(gameboy.load_state(&before[..before.len() - 1]), Err(StateError::UnexpectedEnd));
+    assert_eq!(gameboy.load_state(b"not a state"), Err(StateError::NotAState));
+    assert_eq!(gameboy.save_state(), before);
+}
@@ -0,0 +1,97 @@
+// Compares the screen after a given number of frames with reference
+// screenshots, such as the one shipped with dmg-acid2.
+//
+// Screenshot tests are listed in screenshots.txt at the root of the
+// test ROM directory, one per line:
+//
+//   <rom> <reference png> <frames>
+//
+// Paths are relative to the test ROM directory. On mismatch a diff
+// image is written next to the other test outputs in target/.
+
+mod common;
+
+use std::fmt::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use gb_rs::screenshot;
+use gb_rs::GameBoy;
+
+struct ScreenshotTest {
+    rom: PathBuf,
+    reference: PathBuf,
+    frames: u32,
+}
+
+fn parse_manifest(dir: &Path, manifest: &str) -> Vec<ScreenshotTest> {
+    manifest
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [rom, reference, frames] => ScreenshotTest {
+                rom: dir.join(rom),
+                reference: dir.join(reference),
+                frames: frames
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid frame count: {}", line)),
+            },
+            _ => panic!("Invalid screenshot test: {}", line),
+        })
+        .collect()
+}
+
+fn run_test(test: &ScreenshotTest) -> Result<(), String> {
+    let rom = std::fs::read(&test.rom).map_err(|e| e.to_string())?;
+    let expected = screenshot::load_png_shades(&test.reference).map_err(|e| e.to_string())?;
+
+    let mut gameboy = GameBoy::from_rom_bytes(rom).map_err(|e| e.to_string())?;
+    for _ in 0..test.frames {
+        gameboy.run_frame().map_err(|e| e.to_string())?;
+    }
+
+    let actual = screenshot::to_shades(gameboy.framebuffer());
+    let differences = screenshot::count_differences(&actual, &expected);
+    if differences == 0 {
+        return Ok(());
+    }
+
+    let name = test.reference.file_stem().unwrap_or_default().to_string_lossy();
+    let diff = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}-diff.png", name));
+    screenshot::save_diff_png(&actual, &expected, &diff).map_err(|e| e.to_string())?;
+
+    Err(format!("{} pixels differ, see {}", differences, diff.display()))
+}
+
+#[test]
+fn screenshots() {
+    let dir = common::rom_dir();
+    let manifest = match std::fs::read_to_string(dir.join("screenshots.txt")) {
+        Ok(manifest) => manifest,
+        Err(_) => {
+            eprintln!("No screenshots.txt in {}, skipping", dir.display());
+            return;
+        }
+    };
+
+    let tests = parse_manifest(&dir, &manifest);
+    let results = common::run_parallel(&tests, run_test);
+
+    let mut report = String::new();
+    let mut failures = 0;
+    for (test, result) in tests.iter().zip(results.iter()) {
+        let name = test.rom.strip_prefix(&dir).unwrap_or(&test.rom).display();
+        let result = match result {
+            Ok(()) => "PASS".to_string(),
+            Err(reason) => {
+                failures += 1;
+                format!("FAIL  {}", reason)
+            }
+        };
+        writeln!(report, "{:<60} {}", name, result).unwrap();
+    }
+
+    println!("{}", report);
+    assert_eq!(failures, 0, "{} screenshot(s) did not match", failures);
+}
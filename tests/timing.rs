@@ -0,0 +1,49 @@
+mod common;
+
+use gb_rs::GameBoy;
+
+use common::rom_with_code;
+
+// Restarts the LCD, waits `nops` NOPs then stores LY at 0xC000
+fn ly_after(nops: usize) -> u8 {
+    let mut code = vec![
+        0x21, 0x40, 0xff, // LD HL, 0xFF40
+        0x36, 0x00, // LD (HL), 0x00: LCD off
+        0x3e, 0x91, // LD A, 0x91
+        0x77, // LD (HL), A: writes on its 2nd M-cycle
+    ];
+    code.resize(code.len() + nops, 0x00);
+    code.extend([
+        0xf0, 0x44, // LDH A, (LY): reads on its 3rd M-cycle
+        0xea, 0x00, 0xc0, // LD (0xC000), A
+        0x18, 0xfe, // JR -2
+    ]);
+
+    let mut gameboy = GameBoy::from_rom_bytes(rom_with_code(&code)).unwrap();
+    gameboy.run_frame().unwrap();
+    gameboy.peek(0xc000)
+}
+
+#[test]
+fn memory_accesses_happen_mid_instruction() {
+    // A line lasts 456 cycles: the read comes 4 * nops + 12 cycles after
+    // the write, 4 cycles later than the instruction boundaries suggest
+    assert_eq!(ly_after(110), 0);
+    assert_eq!(ly_after(111), 1);
+}
+
+#[test]
+fn frames_do_not_drift() {
+    // LD (nn), SP takes 20 cycles, which do not divide FRAME_CYCLES:
+    // frames end up to 16 cycles late
+    let mut code = [0x08, 0x00, 0xc0].repeat(200); // LD (0xC000), SP
+    code.extend([0xc3, 0x50, 0x01]); // JP 0x150
+    let mut gameboy = GameBoy::from_rom_bytes(rom_with_code(&code)).unwrap();
+    for _ in 0..100 {
+        gameboy.run_frame().unwrap();
+    }
+
+    // The divider started at 0xABCC, 100 frames later it is 12 cycles
+    // past 0xD300 plus the overshoot of the last frame
+    assert_eq!(gameboy.peek(0xff04), 0xd3);
+}